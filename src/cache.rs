@@ -1,102 +1,294 @@
+mod format;
+mod inspect;
+mod shard;
+
 use std::{
-  collections::HashMap,
+  collections::HashSet,
   fs::File,
   ops::Deref,
+  os::unix::ffi::OsStrExt,
   path::{Path, PathBuf},
   sync::Arc,
   time::SystemTime,
 };
 
 use anyhow::Context;
-use parking_lot::{lock_api::RwLockReadGuard, RwLock};
+use fs4::fs_std::FileExt;
+use parking_lot::{lock_api::RwLockReadGuard, Mutex};
 use serde::{Deserialize, Serialize};
 
-use crate::{ext::ResultExt, fzf::Entry};
+use self::shard::Shard;
+use crate::{
+  fzf::Entry,
+  limits::{Exceeded, Limit},
+};
 
-#[derive(Clone, Default)]
+/// A cache of parsed symbols for a single project.
+///
+/// Files are keyed by their canonical path relative to the project root, so the same
+/// cache is used regardless of which directory inside the project the binary is run from.
+///
+/// Files are split across [`SHARD_COUNT`] shards by the hash of their key. Every search reads
+/// all shards, as cached files are listed before the walk and pruned after it, but shards are
+/// only written if they changed, so the cost of saving a cache depends on the files that changed
+/// rather than on the size of the project.
+///
+/// Clones of a cache share their contents. The cache is only written by [`Cache::save`],
+/// which merges the changes made by this process into the cache on disk, so multiple
+/// processes can share a cache.
+#[derive(Clone)]
 pub struct Cache {
-  path: Option<PathBuf>,
-  files: Arc<RwLock<HashMap<PathBuf, FileInfo>>>,
+  /// The directory containing this project's cache.
+  dir: Option<PathBuf>,
+  root: Option<PathBuf>,
+  shards: Arc<[Shard]>,
+  /// The paths of discarded legacy caches, which are removed once this cache is saved.
+  legacy_paths: Arc<[PathBuf]>,
+  /// Keys of files that were seen during the current walk.
+  visited: Arc<Mutex<HashSet<PathBuf>>>,
 }
 
-const CACHE_FILE_NAME: &str = "cache.json";
+const SHARD_COUNT: usize = 64;
+
+const SHARDS_DIR_NAME: &str = "shards";
+
+/// The file locked while a cache is being saved, so concurrent saves are serialized.
+const LOCK_FILE_NAME: &str = "cache.lock";
+
+/// The file names of caches written before caches were versioned and kept per project,
+/// which were stored directly in the cache directory, and of caches written before caches
+/// were sharded, which were stored in the project's cache directory.
+const LEGACY_CACHE_FILE_NAME: &str = "cache.json";
+const UNSHARDED_CACHE_FILE_NAME: &str = "cache.bin";
 
 #[derive(Serialize, Deserialize)]
 pub struct FileInfo {
   pub modified: SystemTime,
+  /// The size of the file in bytes.
+  pub size: u64,
+  /// The [`content_hash`] of the file.
+  pub hash: u64,
+  /// The [`LanguageConfig::fingerprint`] of the configuration used to parse the file,
+  /// or zero if the file was not parsed.
+  ///
+  /// [`LanguageConfig::fingerprint`]: crate::config::LanguageConfig::fingerprint
+  pub fingerprint: u64,
+  /// The limit exceeded by the file, if it was skipped, in which case it has no entries.
+  pub skipped: Option<Exceeded>,
+  /// The name of the language detected from the file's content, if its language is not
+  /// determined by its path, so the start of the file is not read again while it is unchanged.
+  pub detected: Option<String>,
   /// Cached entries don't contain their own path buffers as it is already
-  /// stored in the [`Cache::files`] field.
+  /// stored as the file's key.
   pub entries: Vec<Entry<(), String>>,
 }
 
 impl Cache {
-  /// Read a cache from a directory containing the cache.
+  /// Opens the cache of the project at `root` in a directory containing project caches.
   ///
-  /// If the directory does not exist or does not contain the cache file,
-  /// the directory and file are created, and a default cache is returned.
-  pub fn from_dir<P: AsRef<Path>>(path: P) -> Result<Self, anyhow::Error> {
-    let path = path.as_ref().join(CACHE_FILE_NAME);
-
-    if !path.exists() {
-      std::fs::create_dir_all(path.parent().context("parent")?).context("create dir")?;
-
-      return Ok(Self {
-        path: Some(path.clone()),
-        files: Arc::default(),
-      });
-    }
+  /// If the directory does not exist, it is created. No shards are read until they are accessed.
+  ///
+  /// Shards that are corrupt or were written with an unsupported format version are discarded.
+  /// Legacy caches don't record the configuration their symbols were parsed with, so they
+  /// can't be migrated and are discarded as well.
+  pub fn from_dir<P: AsRef<Path>>(path: P, root: PathBuf) -> Result<Self, anyhow::Error> {
+    let dir = path.as_ref().join(crate::root::dir_name(&root));
+    let shards_dir = dir.join(SHARDS_DIR_NAME);
 
-    let file = File::open(&path).context("open")?;
+    std::fs::create_dir_all(&shards_dir).context("create dir")?;
+
+    let legacy_paths = [path.as_ref().join(LEGACY_CACHE_FILE_NAME), dir.join(UNSHARDED_CACHE_FILE_NAME)]
+      .into_iter()
+      .filter(|path| path.exists())
+      .collect();
 
     Ok(Self {
-      path: Some(path.clone()),
-      files: Arc::new(RwLock::new(serde_json::from_reader(file).context("failed to parse cache").warn())),
+      shards: (0..SHARD_COUNT)
+        .map(|index| Shard::new(Some(shards_dir.join(format!("{index:02x}.bin")))))
+        .collect(),
+      dir: Some(dir),
+      root: Some(root),
+      legacy_paths,
+      visited: Arc::default(),
     })
   }
 
-  /// Returns the [`FileInfo`] for file at a given path, if any.
-  pub fn file_info(&self, path: &PathBuf) -> Option<impl Deref<Target = FileInfo> + '_> {
-    RwLockReadGuard::try_map(self.files.read(), |files| files.get(path)).ok()
+  /// Returns the key of a file in the cache, which is its canonical path relative to the project root.
+  ///
+  /// Returns `None` if the cache is not persisted or if the file is not inside the project root.
+  pub fn key<P: AsRef<Path>>(&self, path: P) -> Option<PathBuf> {
+    let root = self.root.as_ref()?;
+    let path = std::fs::canonicalize(path).ok()?;
+
+    path.strip_prefix(root).ok().map(Path::to_path_buf)
+  }
+
+  /// Returns the project root, which keys are relative to.
+  pub fn root(&self) -> Option<&Path> {
+    self.root.as_deref()
+  }
+
+  /// Returns the [`FileInfo`] for file with a given key, if any.
+  pub fn file_info(&self, key: &Path) -> Option<impl Deref<Target = FileInfo> + '_> {
+    RwLockReadGuard::try_map(self.shard(key).files().read(), |files| files.get(key)).ok()
+  }
+
+  /// Calls `f` with every cached file, reading all shards.
+  pub fn for_each_file_info(&self, mut f: impl FnMut(&Path, &FileInfo)) {
+    for shard in self.shards.iter() {
+      for (key, file_info) in shard.files().read().iter() {
+        f(key, file_info);
+      }
+    }
   }
 
-  /// Inserts a new [`FileInfo`] for a file at a given path.
-  pub fn new_file_info(&self, path: PathBuf, modified: SystemTime) {
-    self.files.write().insert(
-      path,
-      FileInfo {
-        modified,
-        entries: Vec::new(),
-      },
-    );
+  /// Marks a file as seen during the current walk, so it is kept by [`Cache::prune`].
+  pub fn visit(&self, key: &Path) {
+    self.visited.lock().insert(key.to_path_buf());
   }
 
-  /// Inserts a new [`Entry`] for a file at a given path.
+  /// Removes files inside the directory `dir` that were not seen during the current walk.
   ///
-  /// [`new_file_info`] must be called first.
-  pub fn insert_entry<P, S: Into<String>>(&self, path: &Path, entry: Entry<P, S>) {
+  /// This must only be called after a walk of `dir` completed, as otherwise files that
+  /// were not yet walked are removed. All shards are read, as files that were removed since
+  /// they were cached may be the only files of shards that were not read during the walk.
+  /// Returns the number of files removed.
+  pub fn prune<P: AsRef<Path>>(&self, dir: P) -> usize {
+    let Some(scope) = self.key(dir) else {
+      return 0;
+    };
+
+    let visited = self.visited.lock();
+
     self
-      .files
-      .write()
-      .get_mut(path)
-      .unwrap()
-      .entries
-      .push(Entry::new((), entry.loc, entry.text.into(), entry.kind));
+      .shards
+      .iter()
+      .map(|shard| shard.retain(|key| !key.starts_with(&scope) || visited.contains(key)))
+      .sum()
+  }
+
+  /// Removes all files for which `keep` returns false, reading all shards.
+  /// Returns the number of files removed.
+  pub fn retain(&self, mut keep: impl FnMut(&Path) -> bool) -> usize {
+    self.shards.iter().map(|shard| shard.retain(&mut keep)).sum()
+  }
+
+  /// Inserts a new [`FileInfo`] for a file with a given key.
+  pub fn new_file_info(&self, key: PathBuf, file_info: FileInfo) {
+    let shard = self.shard(&key);
+    let mut changes = shard.changes();
+
+    changes.write(&key);
+
+    shard.files().write().insert(key, file_info);
+  }
+
+  /// Updates the modified time of a file whose content is unchanged.
+  pub fn touch_file_info(&self, key: &Path, modified: SystemTime) {
+    let shard = self.shard(key);
+    let mut changes = shard.changes();
+
+    if let Some(file_info) = shard.files().write().get_mut(key) {
+      file_info.modified = modified;
+
+      changes.write(key);
+    }
   }
 
-  /// Save a cache to its path.
+  /// Saves the shards of this cache that changed.
+  ///
+  /// The cache is locked while saving, so concurrent saves of the same cache are serialized.
   pub fn save(&self) -> Result<(), anyhow::Error> {
-    let Some(path) = &self.path else {
+    let Some(dir) = &self.dir else {
       return Ok(());
     };
 
-    let json = serde_json::to_string(&*self.files.read()).context("to_string")?;
+    let changed: Vec<_> = self.shards.iter().filter(|shard| shard.is_changed()).collect();
+    let legacy_paths: Vec<_> = self.legacy_paths.iter().filter(|path| path.exists()).collect();
+
+    if changed.is_empty() && legacy_paths.is_empty() {
+      return Ok(());
+    }
+
+    let _lock = lock(&dir.join(LOCK_FILE_NAME)).context("lock")?;
+
+    std::fs::create_dir_all(dir.join(SHARDS_DIR_NAME)).context("create dir")?;
+
+    for shard in changed {
+      shard.save().context("save shard")?;
+    }
+
+    for legacy_path in legacy_paths {
+      std::fs::remove_file(legacy_path).context("remove legacy")?;
+    }
+
+    Ok(())
+  }
+
+  /// Returns the shard containing the file with a given key.
+  fn shard(&self, key: &Path) -> &Shard {
+    let hash = xxhash_rust::xxh3::xxh3_64(key.as_os_str().as_bytes());
 
-    std::fs::write(path, json).context("write")
+    &self.shards[(hash % SHARD_COUNT as u64) as usize]
   }
 }
 
-impl Drop for Cache {
-  fn drop(&mut self) {
-    self.save().warn();
+impl Default for Cache {
+  fn default() -> Self {
+    Self {
+      dir: None,
+      root: None,
+      shards: (0..SHARD_COUNT).map(|_| Shard::new(None)).collect(),
+      legacy_paths: Arc::default(),
+      visited: Arc::default(),
+    }
   }
 }
+
+impl FileInfo {
+  /// Returns whether a file with the provided metadata is unchanged since this info was recorded.
+  ///
+  /// The size and modified time are compared first, so `hash` is only called when the size
+  /// matches but the modified time differs. In that case the content hash decides.
+  ///
+  /// Files skipped for their size are fresh as long as their size is unchanged, as they are
+  /// skipped regardless of their content, which is not hashed.
+  pub fn is_fresh(&self, modified: SystemTime, size: u64, hash: impl FnOnce() -> Option<u64>) -> bool {
+    if size != self.size {
+      return false;
+    }
+
+    if modified == self.modified || self.skipped.is_some_and(|skipped| skipped.limit == Limit::FileSize) {
+      return true;
+    }
+
+    hash() == Some(self.hash)
+  }
+
+  /// Returns whether a file with the provided metadata has the same modified time and size as when
+  /// this info was recorded, which doesn't require reading the file.
+  pub fn is_unchanged(&self, modified: SystemTime, size: u64) -> bool {
+    modified == self.modified && size == self.size
+  }
+}
+
+/// A fast, non-cryptographic hash of a file's content.
+pub fn content_hash(content: &[u8]) -> u64 {
+  xxhash_rust::xxh3::xxh3_64(content)
+}
+
+/// Opens the file at `path`, creating it if needed, and acquires an exclusive advisory lock on it.
+///
+/// The lock is released when the returned file is dropped.
+fn lock(path: &Path) -> Result<File, anyhow::Error> {
+  let file = File::options()
+    .create(true)
+    .truncate(false)
+    .write(true)
+    .open(path)
+    .context("open")?;
+
+  file.lock_exclusive().context("lock_exclusive")?;
+
+  Ok(file)
+}
@@ -0,0 +1,208 @@
+use std::{
+  collections::HashMap,
+  ffi::OsStr,
+  fmt::Display,
+  io::Write,
+  os::unix::ffi::OsStrExt,
+  path::{Path, PathBuf},
+  time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::FileInfo;
+use crate::{fzf::Entry, limits::Exceeded, symbol::Kind, text::Span};
+
+/// The first bytes of every cache file.
+const MAGIC: &[u8; 4] = b"KSSC";
+
+/// The version of the format written by [`encode`].
+///
+/// This must be incremented whenever the layout of [`Snapshot`] or any of the types it
+/// contains changes. Caches with older versions are discarded unless [`decode`] knows
+/// how to migrate them.
+pub const VERSION: u32 = 8;
+
+/// The length of the header preceding the encoded [`Snapshot`].
+const HEADER_LEN: usize = MAGIC.len() + std::mem::size_of::<u32>();
+
+/// The encoded contents of a cache.
+///
+/// Symbol texts and path components are interned in [`Snapshot::strings`] and referenced by index,
+/// as many symbols (e.g. `new`, `main`) and directories are repeated across files.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+  strings: Vec<Vec<u8>>,
+  files: Vec<FileRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileRecord {
+  dir: u32,
+  name: u32,
+  modified: SystemTime,
+  size: u64,
+  hash: u64,
+  fingerprint: u64,
+  skipped: Option<Exceeded>,
+  detected: Option<u32>,
+  entries: Vec<EntryRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EntryRecord {
+  span: Span,
+  text: u32,
+  qualified: Option<u32>,
+  kind: Kind,
+  definition: Span,
+  context: Option<u32>,
+  doc: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+  /// The file does not start with [`MAGIC`].
+  NotACache,
+  /// The file was written with a version that cannot be read or migrated.
+  UnsupportedVersion(u32),
+  /// The file has a supported version but its contents are invalid.
+  Corrupt(anyhow::Error),
+}
+
+#[derive(Default)]
+struct Interner<'a> {
+  indices: HashMap<&'a [u8], u32>,
+  strings: Vec<Vec<u8>>,
+}
+
+/// Encodes cached files, writing them with a versioned header to `writer`.
+pub fn encode<W: Write>(files: &HashMap<PathBuf, FileInfo>, mut writer: W) -> Result<(), anyhow::Error> {
+  let mut interner = Interner::default();
+  let mut records = Vec::with_capacity(files.len());
+
+  for (path, file_info) in files {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let name = path.file_name().unwrap_or_default();
+
+    records.push(FileRecord {
+      dir: interner.intern(dir.as_os_str().as_bytes()),
+      name: interner.intern(name.as_bytes()),
+      modified: file_info.modified,
+      size: file_info.size,
+      hash: file_info.hash,
+      fingerprint: file_info.fingerprint,
+      skipped: file_info.skipped,
+      detected: file_info.detected.as_ref().map(|name| interner.intern(name.as_bytes())),
+      entries: file_info
+        .entries
+        .iter()
+        .map(|entry| EntryRecord {
+          span: entry.span,
+          text: interner.intern(entry.text.as_bytes()),
+          qualified: entry.qualified.as_ref().map(|qualified| interner.intern(qualified.as_bytes())),
+          kind: entry.kind,
+          definition: entry.definition,
+          context: entry.context.as_ref().map(|context| interner.intern(context.as_bytes())),
+          doc: entry.doc.as_ref().map(|doc| interner.intern(doc.as_bytes())),
+        })
+        .collect(),
+    });
+  }
+
+  let snapshot = Snapshot {
+    strings: interner.strings,
+    files: records,
+  };
+
+  writer.write_all(MAGIC).context("write magic")?;
+  writer.write_all(&VERSION.to_le_bytes()).context("write version")?;
+
+  bincode::serialize_into(writer, &snapshot).context("serialize")
+}
+
+/// Decodes cached files from the contents of a cache file.
+pub fn decode(bytes: &[u8]) -> Result<HashMap<PathBuf, FileInfo>, DecodeError> {
+  if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+    return Err(DecodeError::NotACache);
+  }
+
+  let version = u32::from_le_bytes(bytes[MAGIC.len()..HEADER_LEN].try_into().unwrap());
+  let body = &bytes[HEADER_LEN..];
+
+  // migrations from older versions should be added here as new arms.
+  match version {
+    VERSION => decode_snapshot(body).map_err(DecodeError::Corrupt),
+    version => Err(DecodeError::UnsupportedVersion(version)),
+  }
+}
+
+fn decode_snapshot(body: &[u8]) -> Result<HashMap<PathBuf, FileInfo>, anyhow::Error> {
+  let Snapshot { strings, files } = bincode::deserialize(body).context("deserialize")?;
+
+  let string = |index: u32| strings.get(index as usize).context("string index out of bounds");
+
+  files
+    .into_iter()
+    .map(|file| {
+      let dir = Path::new(OsStr::from_bytes(string(file.dir)?));
+      let name = OsStr::from_bytes(string(file.name)?);
+
+      let text = |index: u32| Ok::<_, anyhow::Error>(std::str::from_utf8(string(index)?).context("utf8")?.to_string());
+
+      let entries = file
+        .entries
+        .into_iter()
+        .map(|entry| {
+          Ok(Entry {
+            path: (),
+            span: entry.span,
+            text: text(entry.text)?,
+            qualified: entry.qualified.map(text).transpose()?,
+            kind: entry.kind,
+            definition: entry.definition,
+            context: entry.context.map(text).transpose()?,
+            doc: entry.doc.map(text).transpose()?,
+          })
+        })
+        .collect::<Result<_, anyhow::Error>>()?;
+
+      let file_info = FileInfo {
+        modified: file.modified,
+        size: file.size,
+        hash: file.hash,
+        fingerprint: file.fingerprint,
+        skipped: file.skipped,
+        detected: file.detected.map(text).transpose()?,
+        entries,
+      };
+
+      Ok((dir.join(name), file_info))
+    })
+    .collect()
+}
+
+impl<'a> Interner<'a> {
+  fn intern(&mut self, string: &'a [u8]) -> u32 {
+    let strings = &mut self.strings;
+
+    *self.indices.entry(string).or_insert_with(|| {
+      strings.push(string.to_vec());
+
+      (strings.len() - 1) as u32
+    })
+  }
+}
+
+impl Display for DecodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::NotACache => write!(f, "not a cache file"),
+      Self::UnsupportedVersion(version) => write!(f, "unsupported cache version {version} (current version is {VERSION})"),
+      Self::Corrupt(err) => write!(f, "corrupt cache: {err:#}"),
+    }
+  }
+}
+
+impl std::error::Error for DecodeError {}
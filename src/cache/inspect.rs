@@ -0,0 +1,213 @@
+use std::{cell::RefCell, collections::BTreeMap, path::Path};
+
+use anyhow::Context;
+
+use super::{content_hash, lock, Cache, FileInfo, LOCK_FILE_NAME, SHARDS_DIR_NAME};
+use crate::{
+  config::Config,
+  detect,
+  parser::{ParseState, Parser},
+  symbol::Kind,
+};
+
+/// A cached or parsed symbol, in a form that can be compared and sorted.
+type Symbol = (usize, usize, Kind, String);
+
+impl Cache {
+  /// Prints statistics about the cached files and symbols.
+  pub fn print_stats(&self, config: &Config) -> Result<(), anyhow::Error> {
+    let (dir, root) = self.location()?;
+
+    let size: u64 = std::fs::read_dir(dir.join(SHARDS_DIR_NAME))
+      .context("read_dir")?
+      .filter_map(|entry| entry.ok()?.metadata().ok())
+      .map(|metadata| metadata.len())
+      .sum();
+
+    let mut symbols = BTreeMap::<(String, Kind), usize>::new();
+    let (mut files, mut skipped, mut modified, mut missing, mut reconfigured) = (0, 0, 0, 0, 0);
+
+    self.for_each_file_info(|key, file_info| {
+      files += 1;
+      skipped += usize::from(file_info.skipped.is_some());
+
+      let parser = Parser::from_path(config, key, || None, || detect::read_head(root.join(key)));
+      let language = parser.as_ref().map_or("none".to_string(), |parser| parser.language().to_string());
+
+      for entry in &file_info.entries {
+        *symbols.entry((language.clone(), entry.kind)).or_default() += 1;
+      }
+
+      let Ok(metadata) = std::fs::metadata(root.join(key)) else {
+        missing += 1;
+        return;
+      };
+
+      if parser.map_or(0, |parser| parser.fingerprint()) != file_info.fingerprint {
+        reconfigured += 1;
+      } else if metadata.len() != file_info.size || metadata.modified().ok() != Some(file_info.modified) {
+        modified += 1;
+      }
+    });
+
+    println!("root:    {}", root.display());
+    println!("cache:   {} ({size} bytes)", dir.display());
+    println!("files:   {files} ({skipped} skipped for exceeding a limit)");
+    println!("symbols: {}", symbols.values().sum::<usize>());
+    println!("stale:   {modified} modified, {missing} missing, {reconfigured} with a changed configuration");
+
+    if !symbols.is_empty() {
+      println!();
+
+      for ((language, kind), count) in symbols {
+        println!("{language:<12}{kind:<12}{count}");
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Re-parses a sample of at most `sample` cached files and prints the differences between
+  /// their cached and parsed symbols.
+  ///
+  /// Files whose content changed since they were cached are not compared.
+  /// Returns an error if any compared file differs.
+  pub fn verify(&self, config: &Config, sample: usize) -> Result<(), anyhow::Error> {
+    let (_, root) = self.location()?;
+
+    let mut keys = Vec::new();
+    self.for_each_file_info(|key, _| keys.push(key.to_path_buf()));
+    keys.sort();
+
+    let step = (keys.len() / sample.max(1)).max(1);
+    let (mut matched, mut differed, mut stale, mut skipped) = (0, 0, 0, 0);
+    let mut state = ParseState::default();
+
+    for key in keys.iter().step_by(step).take(sample) {
+      let file_info = self.file_info(key).context("file_info")?;
+
+      // files that exceeded a limit have no symbols to compare.
+      if file_info.skipped.is_some() {
+        skipped += 1;
+        continue;
+      }
+
+      let Ok(content) = std::fs::read(root.join(key)) else {
+        stale += 1;
+        continue;
+      };
+
+      if content_hash(&content) != file_info.hash {
+        stale += 1;
+        continue;
+      }
+
+      let mut cached = cached_symbols(&file_info);
+      let mut parsed = parse_symbols(config, &mut state, key, &content).with_context(|| format!("parse {key:?}"))?;
+
+      cached.sort();
+      parsed.sort();
+
+      if cached == parsed {
+        matched += 1;
+        continue;
+      }
+
+      differed += 1;
+
+      println!("{}", key.display());
+
+      for (line, column, kind, text) in cached.iter().filter(|symbol| !parsed.contains(symbol)) {
+        println!("  - {line}:{column} {kind} {text}");
+      }
+
+      for (line, column, kind, text) in parsed.iter().filter(|symbol| !cached.contains(symbol)) {
+        println!("  + {line}:{column} {kind} {text}");
+      }
+    }
+
+    println!("{matched} matched, {differed} differed, {stale} changed since cached, {skipped} skipped");
+
+    anyhow::ensure!(differed == 0, "{differed} files differ from their cached symbols");
+
+    Ok(())
+  }
+
+  /// Prints the cached symbols of the file at `path`.
+  pub fn list<P: AsRef<Path>>(&self, path: P) -> Result<(), anyhow::Error> {
+    let key = self.key(&path).context("file is not in the project")?;
+    let file_info = self.file_info(&key).context("file is not cached")?;
+
+    if let Some(skipped) = file_info.skipped {
+      println!("skipped: {}", skipped.limit);
+    }
+
+    let mut symbols = cached_symbols(&file_info);
+    symbols.sort();
+
+    for (line, column, kind, text) in symbols {
+      println!("{line}:{column} {kind} {text}");
+    }
+
+    Ok(())
+  }
+
+  /// Removes all cached files of this project.
+  pub fn clear(&self) -> Result<(), anyhow::Error> {
+    let (dir, _) = self.location()?;
+    let shards_dir = dir.join(SHARDS_DIR_NAME);
+
+    let _lock = lock(&dir.join(LOCK_FILE_NAME)).context("lock")?;
+
+    if shards_dir.exists() {
+      std::fs::remove_dir_all(shards_dir).context("remove")?;
+    }
+
+    for shard in self.shards.iter() {
+      shard.clear();
+    }
+
+    Ok(())
+  }
+
+  /// Returns the directory of this project's cache and the project root.
+  fn location(&self) -> Result<(&Path, &Path), anyhow::Error> {
+    let path = self.dir.as_deref().context("no cache directory provided")?;
+    let root = self.root.as_deref().context("no project root")?;
+
+    Ok((path, root))
+  }
+}
+
+fn cached_symbols(file_info: &FileInfo) -> Vec<Symbol> {
+  file_info
+    .entries
+    .iter()
+    .map(|entry| (entry.span.start.line, entry.span.start.column, entry.kind, entry.text.clone()))
+    .collect()
+}
+
+fn parse_symbols(config: &Config, state: &mut ParseState, path: &Path, content: &[u8]) -> Result<Vec<Symbol>, anyhow::Error> {
+  let head = || Some(content[..content.len().min(detect::HEAD_LEN)].to_vec());
+
+  let Some(parser) = Parser::from_path(config, path, || None, head) else {
+    return Ok(Vec::new());
+  };
+
+  let content = config.detection().decode(content);
+  let symbols = RefCell::new(Vec::new());
+
+  let tree = parser.parse(state, &content)?;
+
+  parser.on_symbol(state, &content, &tree, |symbol| {
+    let loc = content.original_span(symbol.span).start;
+
+    symbols
+      .borrow_mut()
+      .push((loc.line, loc.column, symbol.kind, symbol.text.to_string()));
+
+    Ok(())
+  })?;
+
+  Ok(symbols.into_inner())
+}
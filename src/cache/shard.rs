@@ -0,0 +1,158 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs::File,
+  io::{BufWriter, Write},
+  path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+
+use super::{format, FileInfo};
+use crate::ext::ResultExt;
+
+/// A segment of a [`Cache`](super::Cache), containing the files whose keys hash to the same shard.
+///
+/// A shard is only read from disk the first time one of its files is accessed,
+/// and is only written if any of its files changed.
+///
+/// When both are locked, `changes` is always locked before `files`.
+pub struct Shard {
+  path: Option<PathBuf>,
+  files: OnceCell<RwLock<HashMap<PathBuf, FileInfo>>>,
+  changes: Mutex<Changes>,
+}
+
+/// Keys of files changed by this process since the shard was last read or saved.
+#[derive(Default)]
+pub struct Changes {
+  written: HashSet<PathBuf>,
+  removed: HashSet<PathBuf>,
+}
+
+impl Shard {
+  pub fn new(path: Option<PathBuf>) -> Self {
+    Self {
+      path,
+      files: OnceCell::new(),
+      changes: Mutex::default(),
+    }
+  }
+
+  /// Returns the files of this shard, reading them from disk if they were not yet read.
+  pub fn files(&self) -> &RwLock<HashMap<PathBuf, FileInfo>> {
+    self.files.get_or_init(|| RwLock::new(self.read()))
+  }
+
+  /// Returns whether any of the files of this shard changed since it was last read or saved.
+  pub fn is_changed(&self) -> bool {
+    !self.changes.lock().is_empty()
+  }
+
+  pub fn changes(&self) -> MutexGuard<'_, Changes> {
+    self.changes.lock()
+  }
+
+  /// Removes all files for which `keep` returns false. Returns the number of files removed.
+  pub fn retain(&self, mut keep: impl FnMut(&Path) -> bool) -> usize {
+    let mut changes = self.changes.lock();
+    let mut files = self.files().write();
+    let len = files.len();
+
+    files.retain(|key, _| {
+      let keep = keep(key);
+
+      if !keep {
+        changes.remove(key);
+      }
+
+      keep
+    });
+
+    len - files.len()
+  }
+
+  /// Saves the shard to its path.
+  ///
+  /// Any changes saved by other processes since this shard was read are merged into this
+  /// shard, except for files changed by this process. The shard's file is replaced atomically
+  /// so readers never observe a partially written shard.
+  ///
+  /// The caller must hold the cache's lock.
+  pub fn save(&self) -> Result<(), anyhow::Error> {
+    let Some(path) = &self.path else {
+      return Ok(());
+    };
+
+    let mut changes = self.changes.lock();
+    let mut files = self.files().write();
+
+    for (key, file_info) in self.read() {
+      if !changes.written.contains(&key) && !changes.removed.contains(&key) {
+        files.insert(key, file_info);
+      }
+    }
+
+    let tmp_path = path.with_extension(format!("{}.tmp", std::process::id()));
+
+    let write = || -> Result<(), anyhow::Error> {
+      let mut writer = BufWriter::new(File::create(&tmp_path).context("create")?);
+
+      format::encode(&files, &mut writer).context("encode")?;
+
+      writer.flush().context("flush")
+    };
+
+    if let Err(err) = write() {
+      std::fs::remove_file(&tmp_path).ok();
+
+      return Err(err);
+    }
+
+    std::fs::rename(&tmp_path, path).context("rename")?;
+
+    *changes = Changes::default();
+
+    Ok(())
+  }
+
+  /// Removes all files of this shard, without recording them as changes.
+  pub fn clear(&self) {
+    let mut changes = self.changes.lock();
+
+    self.files().write().clear();
+    *changes = Changes::default();
+  }
+
+  /// Reads the files of this shard from disk.
+  ///
+  /// Shards that are corrupt or were written with an unsupported format version are discarded.
+  fn read(&self) -> HashMap<PathBuf, FileInfo> {
+    let Some(path) = self.path.as_ref().filter(|path| path.exists()) else {
+      return HashMap::new();
+    };
+
+    std::fs::read(path)
+      .context("read")
+      .and_then(|bytes| Ok(format::decode(&bytes)?))
+      .with_context(|| format!("discarding cache shard {path:?}"))
+      .warn()
+  }
+}
+
+impl Changes {
+  pub fn write(&mut self, key: &Path) {
+    self.removed.remove(key);
+    self.written.insert(key.to_path_buf());
+  }
+
+  pub fn remove(&mut self, key: &Path) {
+    self.written.remove(key);
+    self.removed.insert(key.to_path_buf());
+  }
+
+  fn is_empty(&self) -> bool {
+    self.written.is_empty() && self.removed.is_empty()
+  }
+}
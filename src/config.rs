@@ -1,23 +1,80 @@
-use std::{collections::HashMap, str::FromStr};
+mod error;
+mod layers;
+mod queries;
+mod scopes;
+mod template;
 
-use serde::{Deserialize, Deserializer};
-use tree_sitter::{Language as TreeSitterLanguage, Query};
+use std::{collections::HashMap, ffi::OsString, fmt::Display, path::Path};
 
-use crate::{symbol::Kind, utils::OneOrMany};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use tree_sitter::Language as TreeSitterLanguage;
+use xxhash_rust::xxh3::Xxh3;
+
+use self::queries::{QueryText, RawLanguageConfig};
+pub use self::{error::ConfigError, queries::SymbolQueries, scopes::Scopes};
+use crate::{
+  detect::{self, DetectionConfig},
+  grammar::GrammarConfig,
+  limits::Limits,
+  symbol::Kind,
+};
 
 static DEFAULT_CONFIG: &str = include_str!("../default-config.toml");
 
-#[derive(Deserialize)]
 pub struct Config {
-  #[serde(flatten, deserialize_with = "deserialize_languages")]
   pub languages: HashMap<Language, LanguageConfig>,
+  /// The language of files with a given extension.
+  extensions: HashMap<String, Language>,
+  /// The language of files with a given name.
+  filenames: HashMap<OsString, Language>,
+  /// The language of files matching each glob of `globs`, by index.
+  globs: GlobSet,
+  glob_patterns: Vec<Glob>,
+  glob_languages: Vec<Language>,
+  detection: DetectionConfig,
+  limits: Limits,
+  /// The names of the layers this configuration was merged from.
+  layers: Vec<String>,
+  pub fzf_settings: FzfSettings,
+}
 
+/// The TOML configuration, from which a [`Config`] is built.
+#[derive(Deserialize)]
+struct RawConfig {
+  /// Languages whose grammars are loaded at runtime.
   #[serde(default)]
-  pub fzf_settings: FzfSettings,
+  grammars: HashMap<String, GrammarConfig>,
+
+  /// Files mapped to languages, in addition to the extensions of each language.
+  #[serde(default)]
+  file_types: FileTypesConfig,
+
+  #[serde(default)]
+  detection: DetectionConfig,
+
+  #[serde(default)]
+  limits: Limits,
+
+  #[serde(default)]
+  fzf_settings: FzfSettings,
+
+  #[serde(flatten)]
+  languages: HashMap<String, RawLanguageConfig>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
-#[serde(rename_all = "lowercase")]
+/// Mappings from files to the names of their languages.
+#[derive(Default, Deserialize)]
+struct FileTypesConfig {
+  #[serde(default)]
+  extensions: HashMap<String, String>,
+  #[serde(default)]
+  filenames: HashMap<String, String>,
+  #[serde(default)]
+  globs: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Language {
   C,
   Cpp,
@@ -27,109 +84,446 @@ pub enum Language {
   Python,
   Rust,
   TypeScript,
+  /// A language whose grammar is loaded at runtime.
+  Dynamic(&'static str),
 }
 
 /// A configuration stanza. This structure does not exactly reflect the TOML configuration.
 /// It has this shape for efficiency during file parsing.
 pub struct LanguageConfig {
-  /// Symbols that should be included in the symbol list and which queries match them.
-  pub symbol_queries: HashMap<Kind, Vec<Query>>,
+  pub grammar: TreeSitterLanguage,
+  /// Queries matching the symbols that should be included in the symbol list.
+  pub queries: SymbolQueries,
+  /// Queries matching the scopes whose names qualify the names of symbols.
+  pub scopes: Scopes,
+  /// The kinds from highest to lowest priority.
+  priority: Vec<Kind>,
+  /// A hash of the configuration and of the binary's version.
+  ///
+  /// Cached symbols are only used if they were parsed with a configuration with the same fingerprint.
+  pub fingerprint: u64,
+}
+
+impl Config {
+  /// Returns a regular expression matching the absolute paths of the files inside the project at
+  /// `root` that may have a configured language: files with a configured extension, name or glob,
+  /// and extensionless files if their language can be detected from their content.
+  pub fn file_pattern(&self, root: &Path) -> String {
+    let mut extensions: Vec<_> = self.extensions.keys().map(|extension| regex::escape(extension)).collect();
+    extensions.sort_unstable();
+
+    let mut patterns = vec![format!(r"\.(?:{})$", extensions.join("|"))];
+
+    if self.detection.shebangs || self.detection.modelines {
+      patterns.push(r"/\.?[^./]+$".to_string());
+    }
+
+    for filename in self.filenames.keys() {
+      patterns.push(format!("/{}$", regex::escape(&filename.to_string_lossy())));
+    }
+
+    // globs match paths relative to the project root, as in `language`.
+    let root = regex::escape(&root.to_string_lossy());
+
+    for glob in &self.glob_patterns {
+      let glob = glob.regex().trim_start_matches("(?-u)^");
+
+      patterns.push(format!("(?-u:^{root}/{glob})"));
+    }
+
+    patterns.join("|")
+  }
+
+  /// Returns the language of the file at `path`, if it is configured.
+  ///
+  /// Files are matched by name first, then by glob, then by extension.
+  /// If a file matches multiple globs, the longest one is used. Globs are matched against `path`,
+  /// which must be relative to the project root so a file's language is the same wherever the
+  /// binary is run from.
+  pub fn language<P: AsRef<Path>>(&self, path: P) -> Option<Language> {
+    let path = path.as_ref();
+
+    if let Some(language) = path.file_name().and_then(|name| self.filenames.get(name)) {
+      return Some(*language);
+    }
+
+    if let Some(index) = self.globs.matches(path).into_iter().min() {
+      return Some(self.glob_languages[index]);
+    }
+
+    let extension = path.extension()?.to_str()?;
+
+    self.extensions.get(extension).copied()
+  }
+
+  /// Returns the language of the file at `path`, detecting it from the file's content if it
+  /// can't be determined from its path.
+  ///
+  /// `cached` returns the name of the language previously detected from the file's content, if
+  /// the file is unchanged since. Otherwise, `head` returns the start of the file. They are only
+  /// called for extensionless files without a configured language, and for `.h` files whose
+  /// language is C, and `cached` is called first.
+  pub fn detect_language<P: AsRef<Path>>(
+    &self,
+    path: P,
+    cached: impl FnOnce() -> Option<String>,
+    head: impl FnOnce() -> Option<Vec<u8>>,
+  ) -> Option<Language> {
+    let path = path.as_ref();
+    let language = self.language(path);
+    let cached = || cached().and_then(|name| self.language_named(&name));
+
+    match language {
+      Some(Language::C)
+        if self.detection.headers
+          && path.extension().is_some_and(|extension| extension == "h")
+          && self.languages.contains_key(&Language::Cpp) =>
+      {
+        cached().or_else(|| {
+          let is_cpp = head().is_some_and(|head| detect::is_cpp_header(&String::from_utf8_lossy(&head)));
+
+          Some(if is_cpp { Language::Cpp } else { Language::C })
+        })
+      }
+
+      None if path.extension().is_none() && (self.detection.shebangs || self.detection.modelines) => cached().or_else(|| {
+        let head = head()?;
+        let head = String::from_utf8_lossy(&head);
+
+        let name = None
+          .or_else(|| detect::modeline(&head).filter(|_| self.detection.modelines))
+          .or_else(|| detect::shebang(&head).filter(|_| self.detection.shebangs))?;
+
+        self.language_named(&detect::language_name(name))
+      }),
+
+      language => language,
+    }
+  }
+
+  /// Prints each configured language with its extensions and the number of queries of each kind.
+  pub fn print_summary(&self) {
+    println!("layers: {}", self.layers.join(", "));
+    println!();
+
+    let mut languages: Vec<_> = self.languages.iter().collect();
+    languages.sort_by_key(|(language, _)| language.to_string());
+
+    for (language, language_config) in languages {
+      let mut extensions: Vec<_> = self
+        .extensions
+        .iter()
+        .filter(|(_, l)| *l == language)
+        .map(|(extension, _)| format!(".{extension}"))
+        .collect();
+
+      extensions.sort();
+
+      println!("{language} {}", extensions.join(" "));
+
+      for (kind, count) in language_config.queries.kind_counts() {
+        println!("  {kind:<12}{count}");
+      }
+
+      if language_config.scopes.query_count() > 0 {
+        println!("  {:<12}{}", "scopes", language_config.scopes.query_count());
+      }
+    }
+  }
+
+  /// Returns the configuration of content-based detection.
+  pub fn detection(&self) -> &DetectionConfig {
+    &self.detection
+  }
+
+  /// Returns the limits of the files that are indexed.
+  pub fn limits(&self) -> &Limits {
+    &self.limits
+  }
+
+  /// Returns the configured language with a given name.
+  fn language_named(&self, name: &str) -> Option<Language> {
+    self.languages.keys().find(|language| language.to_string() == name).copied()
+  }
 }
 
 impl Config {
-  pub fn extensions(&self) -> impl Iterator<Item = &'static str> + '_ {
-    self.languages.keys().flat_map(Language::extensions).copied()
+  /// Loads the configuration by merging the default configuration, the user's configuration file,
+  /// the configuration file of the project at `root`, and `config`, each overriding the previous ones.
+  ///
+  /// A kind whose queries are an empty array is removed. A language without any kinds uses the
+  /// tags query bundled with its grammar, and is not indexed if it has none.
+  pub fn load(root: Option<&Path>, config: Option<&str>) -> Result<Self, ConfigError> {
+    let layers = layers::layers(root, config)?;
+    let raw = layers::merge(&layers)?;
+
+    Ok(Self {
+      layers: layers.into_iter().map(|layer| layer.name).collect(),
+      ..Self::from_raw(raw)?
+    })
+  }
+
+  fn from_raw(raw: RawConfig) -> Result<Self, ConfigError> {
+    let mut languages = HashMap::new();
+    let mut extensions = HashMap::new();
+
+    if let Some(name) = raw.grammars.keys().find(|name| Language::from_name(name).is_some()) {
+      return Err(ConfigError::GrammarShadowsLanguage { name: name.clone() });
+    }
+
+    // built-in languages are indexed without being configured, using their bundled tags queries.
+    let mut raw_languages = raw.languages;
+
+    for language in Language::BUILT_IN {
+      raw_languages.entry(language.to_string()).or_default();
+    }
+
+    // languages are built in order, so the same error is reported for the same configuration.
+    let mut raw_languages: Vec<_> = raw_languages.into_iter().collect();
+    raw_languages.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (name, mut raw_language) in raw_languages {
+      let mut hasher = Xxh3::new();
+
+      let (language, grammar, language_extensions) = match (Language::from_name(&name), raw.grammars.get(&name)) {
+        (Some(language), _) => {
+          let language_extensions = language.extensions().iter().map(ToString::to_string).collect();
+
+          (language, language.to_tree_sitter().unwrap(), language_extensions)
+        }
+
+        (None, Some(grammar_config)) => {
+          let grammar = grammar_config.load(&name).map_err(|source| ConfigError::Grammar {
+            language: name.clone(),
+            source,
+          })?;
+
+          grammar_config.hash(&mut hasher);
+
+          let language = Language::Dynamic(Box::leak(name.into_boxed_str()));
+
+          (language, grammar, grammar_config.extensions.clone())
+        }
+
+        (None, None) => return Err(ConfigError::UnknownLanguage { name }),
+      };
+
+      let raw_scopes = raw_language.take_scopes();
+      let priority = raw_language.priority();
+      let texts = raw_language.texts(&language.to_string(), language.tags_query())?;
+
+      if texts.is_empty() {
+        continue;
+      }
+
+      let scopes = raw_scopes.compile(&language.to_string(), &grammar, &mut hasher)?;
+
+      for kind in &priority {
+        hasher.update(format!("{kind:?}").as_bytes());
+      }
+
+      extensions.extend(language_extensions.into_iter().map(|extension| (extension, language)));
+
+      let fingerprint = fingerprint(hasher, language, &texts);
+
+      let queries = SymbolQueries::new(texts, &language.to_string(), &grammar)?;
+
+      languages.insert(
+        language,
+        LanguageConfig {
+          grammar,
+          queries,
+          scopes,
+          priority,
+          fingerprint,
+        },
+      );
+    }
+
+    let language_named = |file_type: String, name: String| match languages.keys().find(|language| language.to_string() == name) {
+      Some(language) => Ok(*language),
+      None => Err(ConfigError::UnconfiguredLanguage { file_type, name }),
+    };
+
+    let FileTypesConfig {
+      extensions: user_extensions,
+      filenames: user_filenames,
+      globs: user_globs,
+    } = raw.file_types;
+
+    for (extension, name) in user_extensions {
+      let language = language_named(format!("extension {extension}"), name)?;
+
+      extensions.insert(extension, language);
+    }
+
+    let mut filenames = HashMap::new();
+
+    for (filename, name) in user_filenames {
+      let language = language_named(format!("filename {filename}"), name)?;
+
+      filenames.insert(OsString::from(filename), language);
+    }
+
+    // globs are sorted from longest to shortest, so the longest matching glob has the lowest index.
+    let mut user_globs: Vec<_> = user_globs.into_iter().collect();
+    user_globs.sort_by(|(a, _), (b, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
+    let mut globs = GlobSetBuilder::new();
+    let mut glob_patterns = Vec::new();
+    let mut glob_languages = Vec::new();
+
+    for (glob, name) in user_globs {
+      let language = language_named(format!("glob {glob}"), name)?;
+      let glob = Glob::new(&glob).map_err(|source| ConfigError::Glob { glob, source })?;
+
+      globs.add(glob.clone());
+      glob_patterns.push(glob);
+      glob_languages.push(language);
+    }
+
+    let globs = globs.build().map_err(|source| ConfigError::Glob {
+      glob: source.glob().unwrap_or_default().to_string(),
+      source,
+    })?;
+
+    Ok(Self {
+      languages,
+      extensions,
+      filenames,
+      globs,
+      glob_patterns,
+      glob_languages,
+      detection: raw.detection,
+      limits: raw.limits,
+      layers: Vec::new(),
+      fzf_settings: raw.fzf_settings,
+    })
   }
 }
 
-impl Default for Config {
-  fn default() -> Self {
-    toml::from_str(DEFAULT_CONFIG).unwrap()
+impl LanguageConfig {
+  /// Returns the rank of a kind in this language's priority, where lower ranks have a higher priority.
+  pub fn rank(&self, kind: Kind) -> usize {
+    self.priority.iter().position(|k| *k == kind).unwrap_or(self.priority.len())
   }
 }
 
 impl Language {
-  pub fn extensions(&self) -> &'static [&'static str] {
+  /// The languages whose grammars are compiled in.
+  const BUILT_IN: [Self; 8] = [
+    Self::C,
+    Self::Cpp,
+    Self::Go,
+    Self::Odin,
+    Self::Haskell,
+    Self::Python,
+    Self::Rust,
+    Self::TypeScript,
+  ];
+
+  /// Returns the built-in language with a given name.
+  pub fn from_name(name: &str) -> Option<Self> {
+    match name {
+      "c" => Some(Self::C),
+      "cpp" => Some(Self::Cpp),
+      "go" => Some(Self::Go),
+      "odin" => Some(Self::Odin),
+      "haskell" => Some(Self::Haskell),
+      "python" => Some(Self::Python),
+      "rust" => Some(Self::Rust),
+      "typescript" => Some(Self::TypeScript),
+      _ => None,
+    }
+  }
+
+  pub fn extensions(self) -> &'static [&'static str] {
     match self {
       Self::C => &["c", "h"],
-      Self::Cpp => &["cpp", "cc", "hh"],
+      Self::Cpp => &["cpp", "cc", "cxx", "c++", "hpp", "hh", "hxx", "h++"],
       Self::Go => &["go"],
       Self::Odin => &["odin"],
       Self::Haskell => &["hs"],
-      Self::Python => &["py"],
+      Self::Python => &["py", "pyi"],
       Self::Rust => &["rs"],
-      Self::TypeScript => &["js", "jsx", "ts", "tsx"],
+      Self::TypeScript => &["js", "jsx", "mjs", "cjs", "ts", "tsx", "mts", "cts"],
+      Self::Dynamic(_) => &[],
     }
   }
 
-  pub fn to_tree_sitter(&self) -> TreeSitterLanguage {
+  /// Returns the grammar of a built-in language.
+  pub fn to_tree_sitter(self) -> Option<TreeSitterLanguage> {
     match self {
-      Self::C => tree_sitter_c::LANGUAGE.into(),
-      Self::Cpp => tree_sitter_cpp::LANGUAGE.into(),
-      Self::Go => tree_sitter_go::LANGUAGE.into(),
-      Self::Haskell => tree_sitter_haskell::LANGUAGE.into(),
-      Self::Odin => tree_sitter_odin::LANGUAGE.into(),
-      Self::Python => tree_sitter_python::LANGUAGE.into(),
-      Self::Rust => tree_sitter_rust::LANGUAGE.into(),
-      Self::TypeScript => tree_sitter_typescript::LANGUAGE_TSX.into(),
+      Self::C => Some(tree_sitter_c::LANGUAGE.into()),
+      Self::Cpp => Some(tree_sitter_cpp::LANGUAGE.into()),
+      Self::Go => Some(tree_sitter_go::LANGUAGE.into()),
+      Self::Haskell => Some(tree_sitter_haskell::LANGUAGE.into()),
+      Self::Odin => Some(tree_sitter_odin::LANGUAGE.into()),
+      Self::Python => Some(tree_sitter_python::LANGUAGE.into()),
+      Self::Rust => Some(tree_sitter_rust::LANGUAGE.into()),
+      Self::TypeScript => Some(tree_sitter_typescript::LANGUAGE_TSX.into()),
+      Self::Dynamic(_) => None,
     }
   }
 
-  pub fn from_extension<S: AsRef<str>>(extension: S) -> Option<Self> {
-    Language::from_str(extension.as_ref()).ok()
+  /// Returns the tags query bundled with the grammar of a built-in language, if it has one.
+  pub fn tags_query(self) -> Option<&'static str> {
+    match self {
+      Self::C => Some(tree_sitter_c::TAGS_QUERY),
+      Self::Cpp => Some(tree_sitter_cpp::TAGS_QUERY),
+      Self::Go => Some(tree_sitter_go::TAGS_QUERY),
+      Self::Python => Some(tree_sitter_python::TAGS_QUERY),
+      Self::Rust => Some(tree_sitter_rust::TAGS_QUERY),
+      Self::TypeScript => Some(tree_sitter_typescript::TAGS_QUERY),
+      Self::Haskell | Self::Odin | Self::Dynamic(_) => None,
+    }
   }
 }
 
-impl FromStr for Language {
-  type Err = ();
-
-  fn from_str(s: &str) -> Result<Self, Self::Err> {
-    match s {
-      "c" | "h" => Ok(Self::C),
-      "cpp" | "cc" | "hh" => Ok(Self::Cpp),
-      "go" => Ok(Self::Go),
-      "odin" => Ok(Self::Odin),
-      "hs" => Ok(Self::Haskell),
-      "py" => Ok(Self::Python),
-      "rs" => Ok(Self::Rust),
-      "js" | "jsx" | "ts" | "tsx" => Ok(Self::TypeScript),
-      _ => Err(()),
+impl Display for Language {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Dynamic(name) => f.pad(name),
+      language => f.pad(&format!("{language:?}").to_lowercase()),
     }
   }
 }
 
-fn deserialize_languages<'de, D>(deserializer: D) -> Result<HashMap<Language, LanguageConfig>, D::Error>
-where
-  D: Deserializer<'de>,
-{
-  let languages = HashMap::<Language, HashMap<Kind, OneOrMany<String>>>::deserialize(deserializer)?;
-
-  Ok(
-    languages
-      .into_iter()
-      .map(|(language, symbol_queries)| {
-        let ts_language = language.to_tree_sitter();
-
-        let symbol_queries: HashMap<Kind, Vec<Query>> = symbol_queries
-          .into_iter()
-          .map(|(symbol_kind, queries)| {
-            let queries = Vec::from(queries);
-            let queries = queries.into_iter().map(|query| Query::new(&ts_language, &query).unwrap()).collect();
-
-            (symbol_kind, queries)
-          })
-          .collect();
-
-        (language, LanguageConfig { symbol_queries })
-      })
-      .collect(),
-  )
+/// Hashes the queries of a language, along with the binary's version, as the symbols produced
+/// by the same queries may change across versions.
+fn fingerprint(mut hasher: Xxh3, language: Language, texts: &[QueryText]) -> u64 {
+  hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+  hasher.update(language.to_string().as_bytes());
+
+  for text in texts {
+    text.hash(&mut hasher);
+  }
+
+  hasher.digest()
 }
 
 #[derive(Deserialize)]
 pub struct FzfSettings {
   #[serde(default = "FzfSettings::default_preview_window")]
   pub preview_window: String,
+
+  #[serde(default)]
+  pub order: Order,
+}
+
+/// The order of the cached files whose symbols are listed before the walk. Files that are not
+/// cached or that changed are listed after them, as soon as they are parsed and in no particular
+/// order. Symbols of the same file are always ordered by position.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+  /// All files are listed as soon as they are walked or parsed, in no particular order.
+  Unordered,
+  /// Cached files are listed by path.
+  #[default]
+  Path,
+  /// Cached files are listed from the most recently modified.
+  Modified,
 }
 
 impl FzfSettings {
@@ -142,6 +536,7 @@ impl Default for FzfSettings {
   fn default() -> Self {
     Self {
       preview_window: Self::default_preview_window(),
+      order: Order::default(),
     }
   }
 }
@@ -0,0 +1,124 @@
+use std::{fmt::Display, path::PathBuf};
+
+use tree_sitter::{QueryError, QueryErrorKind};
+
+/// An invalid configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+  /// A configuration file could not be read.
+  Read { path: PathBuf, source: std::io::Error },
+  /// A layer of the configuration is not valid TOML, or does not have the expected shape.
+  Toml { layer: String, source: toml::de::Error },
+  /// A grammar has the same name as a built-in language.
+  GrammarShadowsLanguage { name: String },
+  /// A language is neither built-in nor has a grammar.
+  UnknownLanguage { name: String },
+  /// A language has a kind that is not a [`Kind`](crate::symbol::Kind).
+  UnknownKind { language: String, source: serde::de::value::Error },
+  /// The queries of a kind are neither a query, a table with a query or a query file, nor an array of them.
+  InvalidQuery { language: String, kind: String, message: String },
+  /// A project's configuration file configures grammars, which only the user can configure.
+  UntrustedGrammars { layer: String },
+  /// A grammar could not be loaded.
+  Grammar { language: String, source: anyhow::Error },
+  /// A query of a language could not be compiled.
+  Query {
+    language: String,
+    /// Where the query comes from, such as its kind and index among the queries of its kind.
+    origin: String,
+    query: String,
+    source: Box<QueryError>,
+  },
+  /// A capture of a query file is not a [`Kind`](crate::symbol::Kind).
+  UnknownCapture { language: String, origin: String, capture: String },
+  /// A query does not have a capture it requires, such as the `@scope` of a scope query.
+  MissingCapture { language: String, origin: String, capture: String },
+  /// A display template is invalid.
+  Template { language: String, origin: String, message: String },
+  /// A file type is mapped to a language that is not configured.
+  UnconfiguredLanguage { file_type: String, name: String },
+  /// A file type glob is invalid.
+  Glob { glob: String, source: globset::Error },
+}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Read { path, source } => write!(f, "failed to read {path:?}: {source}"),
+      Self::Toml { layer, source } => write!(f, "invalid {layer}: {source}"),
+      Self::GrammarShadowsLanguage { name } => write!(f, "grammar {name} has the same name as a built-in language"),
+      Self::UnknownLanguage { name } => write!(f, "unknown language {name}, which has no grammar"),
+      Self::UnknownKind { language, source } => write!(f, "invalid kind in language {language}: {source}"),
+      Self::InvalidQuery { language, kind, message } => {
+        write!(f, "invalid query of kind {kind} in language {language}: {message}")
+      }
+      Self::UntrustedGrammars { layer } => {
+        write!(
+          f,
+          "invalid {layer}: grammars can only be configured by the user's configuration or --config"
+        )
+      }
+      Self::Grammar { language, source } => write!(f, "failed to load the grammar of {language}: {source:#}"),
+      Self::UnconfiguredLanguage { file_type, name } => {
+        write!(f, "{file_type} is mapped to language {name}, which is not configured")
+      }
+      Self::Glob { glob, source } => write!(f, "invalid glob {glob:?}: {source}"),
+
+      Self::UnknownCapture { language, origin, capture } => {
+        write!(
+          f,
+          "invalid capture @{capture} in {origin} of language {language}, which is not a kind"
+        )
+      }
+
+      Self::MissingCapture { language, origin, capture } => {
+        write!(f, "invalid {origin} in language {language}: it has no @{capture} capture")
+      }
+
+      Self::Template { language, origin, message } => {
+        write!(f, "invalid template of {origin} in language {language}: {message}")
+      }
+
+      Self::Query {
+        language,
+        origin,
+        query,
+        source,
+      } => {
+        let QueryError {
+          row,
+          column,
+          kind: error_kind,
+          message,
+          ..
+        } = source.as_ref();
+
+        // syntax and structure errors have a message showing the error's position, which is shown below instead.
+        let error = match error_kind {
+          QueryErrorKind::Syntax => "syntax error".to_string(),
+          QueryErrorKind::Structure => "impossible pattern".to_string(),
+          QueryErrorKind::NodeType => format!("invalid node type `{message}`"),
+          QueryErrorKind::Field => format!("invalid field `{message}`"),
+          QueryErrorKind::Capture => format!("invalid capture `@{message}`"),
+          QueryErrorKind::Predicate => format!("invalid predicate ({message})"),
+          QueryErrorKind::Language => format!("incompatible language ({message})"),
+        };
+
+        write!(
+          f,
+          "invalid {origin} in language {language}: {error} at line {}, column {}",
+          row + 1,
+          column + 1
+        )?;
+
+        if let Some(line) = query.lines().nth(*row) {
+          write!(f, "\n  {line}\n  {:>width$}", "^", width = column + 1)?;
+        }
+
+        Ok(())
+      }
+    }
+  }
+}
+
+impl std::error::Error for ConfigError {}
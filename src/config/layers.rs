@@ -0,0 +1,172 @@
+use std::{
+  io::ErrorKind,
+  path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use super::{ConfigError, RawConfig, DEFAULT_CONFIG};
+
+/// The path of the user's configuration file, relative to the XDG configuration directory.
+const USER_CONFIG_PATH: &str = "kak-symbol-search/config.toml";
+
+/// A layer of the configuration, whose values override the values of the layers below it.
+pub struct Layer {
+  /// Where the layer comes from, for error messages.
+  pub name: String,
+  table: Table,
+}
+
+impl Layer {
+  /// Parses a layer, resolving relative paths against `dir` if the layer comes from a file.
+  fn parse(name: String, content: &str, dir: Option<&Path>) -> Result<Self, ConfigError> {
+    let toml_error = |source| ConfigError::Toml {
+      layer: name.clone(),
+      source,
+    };
+
+    // each layer is checked on its own, so errors point to the file they are in.
+    toml::from_str::<RawConfig>(content).map_err(toml_error)?;
+
+    let mut table: Table = toml::from_str(content).map_err(toml_error)?;
+
+    if let Some(dir) = dir {
+      resolve_paths(&mut table, dir);
+    }
+
+    Ok(Self { name, table })
+  }
+
+  /// Reads the layer at `path`, if the file exists.
+  fn read(path: PathBuf) -> Result<Option<Self>, ConfigError> {
+    let content = match std::fs::read_to_string(&path) {
+      Ok(content) => content,
+      Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+      Err(source) => return Err(ConfigError::Read { path, source }),
+    };
+
+    let dir = path.parent().map(Path::to_path_buf);
+
+    Self::parse(path.display().to_string(), &content, dir.as_deref()).map(Some)
+  }
+}
+
+/// Returns the layers of the configuration, from lowest to highest precedence: the default
+/// configuration, the user's configuration file, the configuration file of the project at
+/// `root`, and the `config` argument.
+///
+/// The configuration file of the project can't configure grammars.
+pub fn layers(root: Option<&Path>, config: Option<&str>) -> Result<Vec<Layer>, ConfigError> {
+  let mut layers = vec![Layer::parse("default configuration".to_string(), DEFAULT_CONFIG, None)?];
+
+  layers.extend(user_config_path().map(Layer::read).transpose()?.flatten());
+
+  let project = root
+    .map(|root| Layer::read(root.join(crate::root::PROJECT_CONFIG_FILE_NAME)))
+    .transpose()?
+    .flatten();
+
+  // loading a grammar runs code from its library, so the configuration of a project, which
+  // comes with whatever repository was cloned, can't load any.
+  if let Some(project) = project.as_ref().filter(|project| project.table.contains_key("grammars")) {
+    return Err(ConfigError::UntrustedGrammars {
+      layer: project.name.clone(),
+    });
+  }
+
+  layers.extend(project);
+
+  if let Some(config) = config {
+    layers.push(Layer::parse("--config".to_string(), config, None)?);
+  }
+
+  Ok(layers)
+}
+
+/// Merges `layers` into a single configuration.
+///
+/// Tables are merged recursively, so each layer only overrides the languages, kinds and
+/// settings it contains. Other values, such as the queries of a kind, are replaced.
+pub fn merge(layers: &[Layer]) -> Result<RawConfig, ConfigError> {
+  let mut table = Table::new();
+
+  for layer in layers {
+    merge_table(&mut table, layer.table.clone());
+  }
+
+  RawConfig::deserialize(Value::Table(table)).map_err(|source| ConfigError::Toml {
+    layer: "merged configuration".to_string(),
+    source,
+  })
+}
+
+fn merge_table(table: &mut Table, layer: Table) {
+  for (key, value) in layer {
+    match (table.get_mut(&key), value) {
+      (Some(Value::Table(table)), Value::Table(layer)) => merge_table(table, layer),
+      (_, value) => {
+        table.insert(key, value);
+      }
+    }
+  }
+}
+
+/// Makes the relative paths of a layer relative to `dir`: grammar libraries, language query
+/// files, and query files of kinds.
+fn resolve_paths(table: &mut Table, dir: &Path) {
+  for (key, value) in table.iter_mut() {
+    let Value::Table(table) = value else {
+      continue;
+    };
+
+    match key.as_str() {
+      "grammars" => {
+        for (_, grammar) in table.iter_mut() {
+          if let Some(library) = grammar.get_mut("library") {
+            resolve_path(library, dir);
+          }
+        }
+      }
+
+      "file_types" | "detection" | "fzf_settings" => {}
+
+      _ => {
+        for (key, value) in table.iter_mut() {
+          match (key.as_str(), value) {
+            ("queries", Value::Array(files)) => files.iter_mut().for_each(|file| resolve_path(file, dir)),
+            ("queries", file) => resolve_path(file, dir),
+            (_, Value::Array(queries)) => queries.iter_mut().for_each(|query| resolve_query_file(query, dir)),
+            (_, query) => resolve_query_file(query, dir),
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Makes the path of a query like `{ file = "path" }` relative to `dir`.
+fn resolve_query_file(query: &mut Value, dir: &Path) {
+  if let Some(file) = query.get_mut("file") {
+    resolve_path(file, dir);
+  }
+}
+
+/// Makes a relative path relative to `dir`.
+fn resolve_path(path: &mut Value, dir: &Path) {
+  if let Value::String(path) = path {
+    if Path::new(path.as_str()).is_relative() {
+      *path = dir.join(path.as_str()).to_string_lossy().into_owned();
+    }
+  }
+}
+
+/// Returns the path of the user's configuration file, in `$XDG_CONFIG_HOME` or `~/.config`.
+fn user_config_path() -> Option<PathBuf> {
+  let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+    .map(PathBuf::from)
+    .filter(|dir| dir.is_absolute())
+    .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+
+  Some(config_dir.join(USER_CONFIG_PATH))
+}
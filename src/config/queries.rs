@@ -0,0 +1,515 @@
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap},
+  path::PathBuf,
+};
+
+use serde::{de::value::StrDeserializer, Deserialize};
+use tree_sitter::{CaptureQuantifier, Language as TreeSitterLanguage, Node, Query, QueryMatch};
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{
+  scopes::{RawScopes, ScopeSource},
+  template::Template,
+  ConfigError,
+};
+use crate::{symbol::Kind, utils::OneOrMany};
+
+/// The TOML configuration of a language.
+#[derive(Default, Deserialize)]
+pub struct RawLanguageConfig {
+  /// Query files whose capture names are the kinds of the captured symbols.
+  #[serde(default)]
+  queries: Option<OneOrMany<PathBuf>>,
+
+  /// Whether to use the tags query bundled with the language's grammar.
+  /// Defaults to true if the language has no other queries.
+  #[serde(default)]
+  use_tags: Option<bool>,
+
+  /// The kinds of this language from highest to lowest priority, which take precedence over [`Kind::PRIORITY`].
+  #[serde(default)]
+  priority: Vec<Kind>,
+
+  /// Queries of the scopes enclosing symbols, whose names qualify the names of the symbols.
+  #[serde(default)]
+  scopes: Option<OneOrMany<ScopeSource>>,
+
+  /// The separator between the names of the scopes of qualified names.
+  #[serde(default)]
+  separator: Option<String>,
+
+  /// Kinds and their queries are validated when the [`Config`](super::Config) is built, so errors
+  /// can name their language and kind.
+  #[serde(flatten)]
+  kinds: HashMap<String, toml::Value>,
+}
+
+/// A query of a kind, either inline or in a file, optionally with a template formatting the
+/// text of its symbols.
+enum QuerySource {
+  Inline(String),
+  File { file: PathBuf, format: Option<String> },
+  Formatted { query: String, format: String },
+}
+
+/// A query of a kind written as a table, such as `{ file = "function.scm", format = "{name}()" }`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct QueryTable {
+  query: Option<String>,
+  file: Option<PathBuf>,
+  format: Option<String>,
+}
+
+/// The text of a query, along with the kind of its captures.
+pub struct QueryText {
+  /// The kind of all of the query's captures, or `None` if each capture's name is its kind.
+  kind: Option<Kind>,
+  /// Where the query comes from, for error messages.
+  origin: String,
+  text: String,
+  /// The template of the text of the query's symbols, such as `impl {trait} for {name}`.
+  format: Option<String>,
+  /// Whether the query is bundled with a grammar, in which case captures that are not
+  /// kinds are ignored rather than rejected.
+  bundled: bool,
+}
+
+/// The queries of a language, combined into a single query so that trees are only walked once.
+pub struct SymbolQueries {
+  pub query: Query,
+  /// The index of the query of each pattern of the combined query, by pattern index.
+  patterns: Vec<usize>,
+  queries: Vec<SymbolQuery>,
+}
+
+/// A query whose captures are symbols, whose patterns are part of a [`SymbolQueries`].
+///
+/// Capture indices are the indices of the captures of the combined query.
+pub struct SymbolQuery {
+  /// The kind of the query's symbols, or `None` if each capture's name is its kind.
+  kind: Option<Kind>,
+  /// What each of the query's captures is, by capture index.
+  captures: Vec<Capture>,
+  /// The template of the text of the query's symbols, expanded from the captures of each match.
+  format: Option<Template>,
+  /// Whether the query has a `@name` capture.
+  ///
+  /// If it does, each match is a single symbol, whose text is the `@name` capture and whose
+  /// kind is the query's kind, or the kind of another capture of the match, such as `@definition.function`.
+  /// Otherwise, each capture with a kind is a symbol.
+  name: bool,
+}
+
+/// What a capture of a [`SymbolQuery`] is.
+#[derive(Clone, Copy)]
+enum Capture {
+  /// Not part of a symbol, such as captures only used in predicates.
+  Ignored,
+  /// A symbol of a kind, or the kind of the match's `@name` symbol.
+  Kind(Kind),
+  /// The `@name` of the match's symbol.
+  Name,
+  /// The full `@definition` of the match's symbol, and its kind for `@definition.<kind>` captures.
+  Definition(Option<Kind>),
+  /// The name of the scope enclosing the match's symbol, captured by `@context` or `@parent`.
+  Context,
+  /// The `@doc` comment of the match's symbol.
+  Doc,
+}
+
+/// A symbol matched by a [`SymbolQuery`].
+pub struct SymbolMatch<'tree> {
+  pub name: Node<'tree>,
+  pub kind: Kind,
+  pub definition: Option<Node<'tree>>,
+  pub context: Option<Node<'tree>>,
+  pub doc: Option<Node<'tree>>,
+}
+
+impl RawLanguageConfig {
+  /// Takes the configuration of this language's scopes, which are compiled separately from its queries.
+  pub fn take_scopes(&mut self) -> RawScopes {
+    RawScopes {
+      queries: self.scopes.take(),
+      separator: self.separator.take(),
+    }
+  }
+
+  /// Returns the kinds of this language from highest to lowest priority: the kinds of its `priority`,
+  /// followed by the other kinds in their default order.
+  pub fn priority(&self) -> Vec<Kind> {
+    let mut priority = self.priority.clone();
+
+    for kind in Kind::PRIORITY {
+      if !priority.contains(&kind) {
+        priority.push(kind);
+      }
+    }
+
+    priority
+  }
+
+  /// Returns the text of this language's queries, reading query files.
+  ///
+  /// Queries of kinds are ordered by kind, and are followed by query files and the
+  /// language's bundled `tags` query, if it is used.
+  pub fn texts(self, language: &str, tags: Option<&str>) -> Result<Vec<QueryText>, ConfigError> {
+    let mut kinds = self
+      .kinds
+      .into_iter()
+      .map(|(kind, queries)| {
+        let kind = Kind::deserialize(StrDeserializer::new(&kind)).map_err(|source| ConfigError::UnknownKind {
+          language: language.to_string(),
+          source,
+        })?;
+
+        let queries = QuerySource::parse_all(queries).map_err(|message| ConfigError::InvalidQuery {
+          language: language.to_string(),
+          kind: kind.to_string(),
+          message,
+        })?;
+
+        Ok((kind, queries))
+      })
+      .collect::<Result<Vec<_>, ConfigError>>()?;
+
+    kinds.sort_by_key(|(kind, _)| *kind);
+
+    let mut texts = Vec::new();
+
+    for (kind, queries) in kinds {
+      for (index, query) in queries.into_iter().enumerate() {
+        let (origin, text, format) = match query {
+          QuerySource::Inline(text) => (format!("query {index} of kind {kind}"), text, None),
+          QuerySource::Formatted { query, format } => (format!("query {index} of kind {kind}"), query, Some(format)),
+          QuerySource::File { file, format } => (format!("query file {file:?} of kind {kind}"), read(file)?, format),
+        };
+
+        texts.push(QueryText {
+          kind: Some(kind),
+          origin,
+          text,
+          format,
+          bundled: false,
+        });
+      }
+    }
+
+    for file in self.queries.map(Vec::from).unwrap_or_default() {
+      texts.push(QueryText {
+        kind: None,
+        origin: format!("query file {file:?}"),
+        text: read(file)?,
+        format: None,
+        bundled: false,
+      });
+    }
+
+    if let Some(tags) = tags.filter(|_| self.use_tags.unwrap_or(texts.is_empty())) {
+      texts.push(QueryText {
+        kind: None,
+        origin: "bundled tags query".to_string(),
+        text: tags.to_string(),
+        format: None,
+        bundled: true,
+      });
+    }
+
+    Ok(texts)
+  }
+}
+
+impl QuerySource {
+  /// Parses the queries of a kind, which are a query or an array of queries.
+  fn parse_all(value: toml::Value) -> Result<Vec<Self>, String> {
+    match value {
+      toml::Value::Array(values) => values.into_iter().map(Self::parse).collect(),
+      value => Ok(vec![Self::parse(value)?]),
+    }
+  }
+
+  /// Parses a query, which is either a string or a table with a `query` or a `file`.
+  fn parse(value: toml::Value) -> Result<Self, String> {
+    if let toml::Value::String(query) = value {
+      return Ok(Self::Inline(query));
+    }
+
+    if !value.is_table() {
+      return Err(format!(
+        "expected a query, or a table with a `query` or a `file`, found {}",
+        value.type_str()
+      ));
+    }
+
+    let QueryTable { query, file, format } = value.try_into().map_err(|err: toml::de::Error| err.message().to_string())?;
+
+    match (query, file, format) {
+      (Some(query), None, Some(format)) => Ok(Self::Formatted { query, format }),
+      (Some(query), None, None) => Ok(Self::Inline(query)),
+      (None, Some(file), format) => Ok(Self::File { file, format }),
+      _ => Err("expected a table with either a `query` or a `file`".to_string()),
+    }
+  }
+}
+
+impl SymbolQueries {
+  /// Compiles the queries of a language into a single query.
+  pub fn new(texts: Vec<QueryText>, language: &str, grammar: &TreeSitterLanguage) -> Result<Self, ConfigError> {
+    let sources: Vec<_> = texts.iter().map(|text| (text.origin.as_str(), text.text.as_str())).collect();
+    let (query, patterns) = combine(&sources, language, grammar)?;
+
+    let queries = texts
+      .into_iter()
+      .enumerate()
+      .map(|(index, text)| text.compile(&query, &used_captures(&query, &patterns, index), language))
+      .collect::<Result<_, _>>()?;
+
+    Ok(Self { query, patterns, queries })
+  }
+
+  /// Returns the query of a match, along with its index.
+  pub fn query_of(&self, m: &QueryMatch) -> (usize, &SymbolQuery) {
+    let index = self.patterns[m.pattern_index];
+
+    (index, &self.queries[index])
+  }
+
+  /// Returns the number of queries capturing each kind of symbol.
+  pub fn kind_counts(&self) -> BTreeMap<Kind, usize> {
+    let mut counts = BTreeMap::new();
+
+    for query in &self.queries {
+      for kind in query.kinds().collect::<BTreeSet<_>>() {
+        *counts.entry(kind).or_default() += 1;
+      }
+    }
+
+    counts
+  }
+}
+
+impl QueryText {
+  /// Compiles this query, whose patterns are part of the combined `query`, where `used` is
+  /// whether each capture of `query` is used by this query's patterns.
+  ///
+  /// Captures whose name starts with an underscore are not symbols, so they can be used in predicates.
+  fn compile(self, query: &Query, used: &[bool], language: &str) -> Result<SymbolQuery, ConfigError> {
+    let capture_index = |name: &str| query.capture_index_for_name(name).filter(|index| used[*index as usize]);
+
+    let format = self
+      .format
+      .as_deref()
+      .map(|format| {
+        Template::parse(format, capture_index).map_err(|message| ConfigError::Template {
+          language: language.to_string(),
+          origin: self.origin.clone(),
+          message,
+        })
+      })
+      .transpose()?;
+
+    let mut captures = Vec::new();
+    let mut name = false;
+
+    for (index, capture) in query.capture_names().iter().enumerate() {
+      let capture = match *capture {
+        _ if !used[index] => Capture::Ignored,
+        capture if capture.starts_with('_') => Capture::Ignored,
+        "name" => {
+          name = true;
+          Capture::Name
+        }
+        "definition" => Capture::Definition(None),
+        "context" | "parent" => Capture::Context,
+        "doc" => Capture::Doc,
+        capture => match (self.kind, capture.strip_prefix("definition.")) {
+          (Some(_), Some(_)) => Capture::Definition(None),
+          (None, Some(kind)) => Capture::Definition(Some(capture_kind(kind).ok_or_else(|| self.unknown_capture(language, capture))?)),
+          (Some(kind), None) => Capture::Kind(kind),
+          (None, None) => match capture_kind(capture) {
+            Some(kind) => Capture::Kind(kind),
+            None if self.bundled => Capture::Ignored,
+            None => return Err(self.unknown_capture(language, capture)),
+          },
+        },
+      };
+
+      captures.push(capture);
+    }
+
+    // the position of formatted symbols is their `@name`, as the other captures are only used by the template.
+    if format.is_some() && !name {
+      return Err(ConfigError::MissingCapture {
+        language: language.to_string(),
+        origin: self.origin,
+        capture: "name".to_string(),
+      });
+    }
+
+    Ok(SymbolQuery {
+      kind: self.kind,
+      captures,
+      format,
+      name,
+    })
+  }
+
+  fn unknown_capture(&self, language: &str, capture: &str) -> ConfigError {
+    ConfigError::UnknownCapture {
+      language: language.to_string(),
+      origin: self.origin.clone(),
+      capture: capture.to_string(),
+    }
+  }
+
+  /// Hashes the query and the kind of its captures.
+  pub fn hash(&self, hasher: &mut Xxh3) {
+    match self.kind {
+      Some(kind) => hasher.update(format!("{kind:?}").as_bytes()),
+      None => hasher.update(b"captures"),
+    }
+
+    for part in [Some(&self.text), self.format.as_ref()].into_iter().flatten() {
+      hasher.update(&(part.len() as u64).to_le_bytes());
+      hasher.update(part.as_bytes());
+    }
+  }
+}
+
+impl SymbolQuery {
+  /// Returns the kinds of symbols captured by this query.
+  pub fn kinds(&self) -> impl Iterator<Item = Kind> + '_ {
+    self.kind.into_iter().chain(self.captures.iter().filter_map(Capture::kind))
+  }
+
+  /// Returns the text of the symbols of a match of this query, if the query has a template
+  /// and the match has all of its captures.
+  pub fn format(&self, m: &QueryMatch, content: &str) -> Option<String> {
+    self.format.as_ref()?.expand(m, content)
+  }
+
+  /// Returns the symbols of a match of this query.
+  pub fn symbols<'a, 'tree>(&'a self, m: &'a QueryMatch<'_, 'tree>) -> impl Iterator<Item = SymbolMatch<'tree>> + 'a {
+    let node = |role: fn(&Capture) -> bool| {
+      m.captures
+        .iter()
+        .find(|capture| role(&self.captures[capture.index as usize]))
+        .map(|capture| capture.node)
+    };
+
+    let definition = node(|capture| matches!(capture, Capture::Definition(_)));
+    let context = node(|capture| matches!(capture, Capture::Context));
+    let doc = node(|capture| matches!(capture, Capture::Doc));
+
+    let symbol = move |name, kind| SymbolMatch {
+      name,
+      kind,
+      definition,
+      context,
+      doc,
+    };
+
+    let named = self
+      .name
+      .then(|| {
+        let name = node(|capture| matches!(capture, Capture::Name))?;
+        let kind = self
+          .kind
+          .or_else(|| m.captures.iter().find_map(|capture| self.captures[capture.index as usize].kind()))?;
+
+        Some(symbol(name, kind))
+      })
+      .flatten();
+
+    let captures = m
+      .captures
+      .iter()
+      .filter(|_| !self.name)
+      .filter_map(move |capture| match self.captures[capture.index as usize] {
+        Capture::Kind(kind) => Some(symbol(capture.node, kind)),
+        _ => None,
+      });
+
+    named.into_iter().chain(captures)
+  }
+}
+
+impl Capture {
+  fn kind(&self) -> Option<Kind> {
+    match self {
+      Self::Kind(kind) | Self::Definition(Some(kind)) => Some(*kind),
+      _ => None,
+    }
+  }
+}
+
+/// Returns the kind of a capture of a query whose capture names are kinds.
+///
+/// Captures following the `tags` convention, such as `@definition.function`, are also kinds.
+fn capture_kind(capture: &str) -> Option<Kind> {
+  let capture = capture.strip_prefix("definition.").unwrap_or(capture);
+
+  Kind::deserialize(StrDeserializer::<serde::de::value::Error>::new(capture)).ok()
+}
+
+/// Combines queries, given by their origin and text, into a single query. Returns the combined query
+/// along with the index of the query of each of its patterns.
+///
+/// If the combined query is invalid, the error of the first invalid query is returned, so its
+/// position is relative to that query.
+pub fn combine(queries: &[(&str, &str)], language: &str, grammar: &TreeSitterLanguage) -> Result<(Query, Vec<usize>), ConfigError> {
+  let query_error = |origin: &str, query: &str, source| ConfigError::Query {
+    language: language.to_string(),
+    origin: origin.to_string(),
+    query: query.to_string(),
+    source: Box::new(source),
+  };
+
+  let mut combined = String::new();
+  let mut starts = Vec::new();
+
+  for (_, text) in queries {
+    starts.push(combined.len());
+    combined.push_str(text);
+    combined.push('\n');
+  }
+
+  let query = match Query::new(grammar, &combined) {
+    Ok(query) => query,
+    Err(source) => {
+      for (origin, text) in queries {
+        Query::new(grammar, text).map_err(|source| query_error(origin, text, source))?;
+      }
+
+      return Err(query_error("combination of queries", &combined, source));
+    }
+  };
+
+  let patterns = (0..query.pattern_count())
+    .map(|pattern| {
+      let start = query.start_byte_for_pattern(pattern);
+
+      starts.partition_point(|query_start| *query_start <= start) - 1
+    })
+    .collect();
+
+  Ok((query, patterns))
+}
+
+/// Returns whether each capture of a combined query is used by the patterns of the query with a given index.
+pub fn used_captures(query: &Query, patterns: &[usize], index: usize) -> Vec<bool> {
+  let mut used = vec![false; query.capture_names().len()];
+
+  for (pattern, _) in patterns.iter().enumerate().filter(|(_, query_index)| **query_index == index) {
+    for (capture, quantifier) in query.capture_quantifiers(pattern).iter().enumerate() {
+      used[capture] |= *quantifier != CaptureQuantifier::Zero;
+    }
+  }
+
+  used
+}
+
+fn read(path: PathBuf) -> Result<String, ConfigError> {
+  std::fs::read_to_string(&path).map_err(|source| ConfigError::Read { path, source })
+}
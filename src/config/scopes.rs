@@ -0,0 +1,153 @@
+use serde::Deserialize;
+use tree_sitter::{Language as TreeSitterLanguage, Node, Query, QueryMatch};
+use xxhash_rust::xxh3::Xxh3;
+
+use super::{
+  queries::{combine, used_captures},
+  template::Template,
+  ConfigError,
+};
+use crate::{text, utils::OneOrMany};
+
+/// The separator of qualified names of languages that don't configure one.
+const DEFAULT_SEPARATOR: &str = "::";
+
+/// The TOML configuration of the scopes of a language.
+pub struct RawScopes {
+  pub queries: Option<OneOrMany<ScopeSource>>,
+  pub separator: Option<String>,
+}
+
+/// A query of scopes, capturing each scope as `@scope`.
+///
+/// The name of a scope is its `@name` capture, or the expansion of `name`, such as `<{type} as {trait}>`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ScopeSource {
+  Query(String),
+  Named { query: String, name: String },
+}
+
+/// The compiled scope queries of a language, combined into a single query.
+pub struct Scopes {
+  /// The combined query, or `None` if the language has no scope queries.
+  pub query: Option<Query>,
+  /// The index of the query of each pattern of the combined query, by pattern index.
+  patterns: Vec<usize>,
+  /// The name of the scopes of each query.
+  names: Vec<ScopeName>,
+  /// The index of the `@scope` capture.
+  scope: u32,
+  /// The separator between the names of the scopes of qualified names.
+  pub separator: String,
+}
+
+enum ScopeName {
+  /// The index of the `@name` capture.
+  Capture(u32),
+  Template(Template),
+}
+
+impl RawScopes {
+  /// Compiles the scope queries of a language, adding them to `hasher`.
+  pub fn compile(self, language: &str, grammar: &TreeSitterLanguage, hasher: &mut Xxh3) -> Result<Scopes, ConfigError> {
+    let separator = self.separator.unwrap_or_else(|| DEFAULT_SEPARATOR.to_string());
+
+    hasher.update(&(separator.len() as u64).to_le_bytes());
+    hasher.update(separator.as_bytes());
+
+    let sources: Vec<_> = self
+      .queries
+      .map(Vec::from)
+      .unwrap_or_default()
+      .into_iter()
+      .enumerate()
+      .map(|(index, source)| {
+        let (text, template) = match source {
+          ScopeSource::Query(text) => (text, None),
+          ScopeSource::Named { query, name } => (query, Some(name)),
+        };
+
+        for part in [Some(&text), template.as_ref()].into_iter().flatten() {
+          hasher.update(&(part.len() as u64).to_le_bytes());
+          hasher.update(part.as_bytes());
+        }
+
+        (format!("scope query {index}"), text, template)
+      })
+      .collect();
+
+    if sources.is_empty() {
+      return Ok(Scopes {
+        query: None,
+        patterns: Vec::new(),
+        names: Vec::new(),
+        scope: 0,
+        separator,
+      });
+    }
+
+    let texts: Vec<_> = sources.iter().map(|(origin, text, _)| (origin.as_str(), text.as_str())).collect();
+    let (query, patterns) = combine(&texts, language, grammar)?;
+
+    let mut names = Vec::new();
+
+    for (index, (origin, _, template)) in sources.iter().enumerate() {
+      let used = used_captures(&query, &patterns, index);
+      let capture_index = |name: &str| query.capture_index_for_name(name).filter(|index| used[*index as usize]);
+
+      let missing_capture = |capture: &str| ConfigError::MissingCapture {
+        language: language.to_string(),
+        origin: origin.to_string(),
+        capture: capture.to_string(),
+      };
+
+      capture_index("scope").ok_or_else(|| missing_capture("scope"))?;
+
+      let name = match template {
+        Some(template) => ScopeName::Template(Template::parse(template, capture_index).map_err(|message| ConfigError::Template {
+          language: language.to_string(),
+          origin: origin.to_string(),
+          message,
+        })?),
+
+        None => ScopeName::Capture(capture_index("name").ok_or_else(|| missing_capture("name"))?),
+      };
+
+      names.push(name);
+    }
+
+    // every query uses `@scope`, so the combined query has it.
+    let scope = query.capture_index_for_name("scope").unwrap();
+
+    Ok(Scopes {
+      query: Some(query),
+      patterns,
+      names,
+      scope,
+      separator,
+    })
+  }
+}
+
+impl Scopes {
+  /// Returns the number of scope queries.
+  pub fn query_count(&self) -> usize {
+    self.names.len()
+  }
+
+  /// Returns the scope of a match of the combined query along with its name, and the index of
+  /// the query it matched.
+  pub fn scope<'tree>(&self, m: &QueryMatch<'_, 'tree>, content: &str) -> Option<(usize, Node<'tree>, String)> {
+    let node = |index: u32| m.captures.iter().find(|capture| capture.index == index).map(|capture| capture.node);
+
+    let index = self.patterns[m.pattern_index];
+    let scope = node(self.scope)?;
+    let name = match &self.names[index] {
+      ScopeName::Capture(capture) => text::single_line(&content[node(*capture)?.byte_range()]),
+      ScopeName::Template(template) => template.expand(m, content)?,
+    };
+
+    Some((index, scope, name))
+  }
+}
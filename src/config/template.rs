@@ -0,0 +1,85 @@
+use tree_sitter::QueryMatch;
+
+use crate::text;
+
+/// A display template, such as `<{type} as {trait}>`, whose placeholders are replaced by the
+/// text of the captures of a match.
+///
+/// Braces are escaped by doubling them.
+pub struct Template {
+  parts: Vec<Part>,
+}
+
+enum Part {
+  Literal(String),
+  /// The index of a capture.
+  Capture(u32),
+}
+
+impl Template {
+  /// Parses `template`, resolving each placeholder to the index of a capture with `capture`.
+  pub fn parse(template: &str, capture: impl Fn(&str) -> Option<u32>) -> Result<Self, String> {
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars();
+
+    while let Some(char) = chars.next() {
+      match char {
+        '{' if chars.as_str().starts_with('{') => {
+          chars.next();
+          literal.push('{');
+        }
+
+        '}' if chars.as_str().starts_with('}') => {
+          chars.next();
+          literal.push('}');
+        }
+
+        '{' => {
+          let (name, rest) = chars
+            .as_str()
+            .split_once('}')
+            .ok_or_else(|| format!("unclosed placeholder in {template:?}"))?;
+
+          let index = capture(name).ok_or_else(|| format!("placeholder {{{name}}} in {template:?} is not a capture of the query"))?;
+
+          if !literal.is_empty() {
+            parts.push(Part::Literal(std::mem::take(&mut literal)));
+          }
+
+          parts.push(Part::Capture(index));
+          chars = rest.chars();
+        }
+
+        '}' => return Err(format!("unmatched `}}` in {template:?}")),
+
+        char => literal.push(char),
+      }
+    }
+
+    if !literal.is_empty() {
+      parts.push(Part::Literal(literal));
+    }
+
+    Ok(Self { parts })
+  }
+
+  /// Expands this template with the captures of a match, or returns `None` if any of its
+  /// captures is missing from the match.
+  pub fn expand(&self, m: &QueryMatch, content: &str) -> Option<String> {
+    let mut expanded = String::new();
+
+    for part in &self.parts {
+      match part {
+        Part::Literal(literal) => expanded.push_str(literal),
+        Part::Capture(index) => {
+          let capture = m.captures.iter().find(|capture| capture.index == *index)?;
+
+          expanded.push_str(&text::single_line(&content[capture.node.byte_range()]));
+        }
+      }
+    }
+
+    Some(expanded)
+  }
+}
@@ -0,0 +1,389 @@
+use std::{borrow::Cow, fs::File, io::Read, ops::Deref, path::Path};
+
+use encoding_rs::Encoding;
+use serde::{de::Error, Deserialize, Deserializer};
+
+use crate::text::{Loc, Span};
+
+/// The number of bytes read from the start of a file to detect its language.
+pub const HEAD_LEN: usize = 8 * 1024;
+
+/// The number of lines searched for a modeline.
+const MODELINE_LINES: usize = 5;
+
+/// Which kinds of content-based language detection are enabled.
+#[derive(Clone, Copy, Deserialize)]
+pub struct DetectionConfig {
+  /// Detect the language of extensionless files from their shebang.
+  #[serde(default = "DetectionConfig::enabled")]
+  pub shebangs: bool,
+  /// Detect the language of extensionless files from a Kakoune, Vim or Emacs modeline.
+  #[serde(default = "DetectionConfig::enabled")]
+  pub modelines: bool,
+  /// Parse `.h` files containing C++ constructs as C++ rather than C.
+  #[serde(default = "DetectionConfig::enabled")]
+  pub headers: bool,
+  /// Skip files whose start contains a NUL byte, which are assumed to be binary.
+  #[serde(default = "DetectionConfig::enabled")]
+  pub binary: bool,
+  /// The encoding of files that are not valid UTF-8. Such files are decoded lossily if it is not set.
+  #[serde(default, deserialize_with = "deserialize_encoding")]
+  pub encoding: Option<&'static Encoding>,
+}
+
+impl DetectionConfig {
+  fn enabled() -> bool {
+    true
+  }
+
+  /// Decodes the content of a file, which is either UTF-8 or in the configured encoding.
+  ///
+  /// Without an encoding, invalid UTF-8 is decoded lossily, replacing each invalid sequence.
+  pub fn decode<'a>(&self, content: &'a [u8]) -> Decoded<'a> {
+    match std::str::from_utf8(content) {
+      Ok(text) => Decoded {
+        text: Cow::Borrowed(text),
+        shifts: Vec::new(),
+      },
+      Err(_) => Decoded::new(self.encoding.unwrap_or(encoding_rs::UTF_8), content),
+    }
+  }
+}
+
+/// The content of a file decoded to UTF-8, whose columns may differ from the file's.
+pub struct Decoded<'a> {
+  text: Cow<'a, str>,
+  /// The positions following each character whose decoded length differs from its length in the file.
+  shifts: Vec<Shift>,
+}
+
+/// A position of decoded text, after which its columns differ from the columns of the file.
+struct Shift {
+  /// The 0-based line of the position.
+  line: usize,
+  /// The 0-based column of the position in the decoded text.
+  decoded: usize,
+  /// The 0-based column of the position in the file.
+  original: usize,
+}
+
+impl Decoded<'_> {
+  fn new(encoding: &'static Encoding, content: &[u8]) -> Self {
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut text = String::with_capacity(content.len());
+    let mut shifts = Vec::new();
+
+    let (mut line, mut decoded, mut original) = (0, 0, 0);
+
+    // moves past decoded characters read from a number of bytes.
+    let mut advance = |chars: &str, bytes: usize| match chars.rfind('\n') {
+      // characters following a newline decoded along with it are assumed to have the same length in the file.
+      Some(newline) => {
+        line += chars.matches('\n').count();
+        decoded = chars.len() - newline - 1;
+        original = decoded;
+      }
+
+      None => {
+        decoded += chars.len();
+        original += bytes;
+
+        if chars.len() != bytes {
+          shifts.push(Shift { line, decoded, original });
+        }
+      }
+    };
+
+    // the number of bytes read since the last decoded character.
+    let mut pending = 0;
+
+    // bytes are decoded one at a time, so the bytes of each decoded character are known.
+    for (index, byte) in content.iter().enumerate() {
+      let start = text.len();
+
+      // enough space is reserved for the byte to always be read.
+      text.reserve(decoder.max_utf8_buffer_length(1).unwrap_or(16));
+      let _ = decoder.decode_to_string(std::slice::from_ref(byte), &mut text, index + 1 == content.len());
+      pending += 1;
+
+      let chars = &text[start..];
+
+      // an ASCII character decoded along with others, such as after an invalid sequence, is the byte just read.
+      match chars.char_indices().next_back() {
+        None => continue,
+        Some((last, char)) if last > 0 && char.is_ascii() => {
+          advance(&chars[..last], pending - 1);
+          advance(&chars[last..], 1);
+        }
+        Some(_) => advance(chars, pending),
+      }
+
+      pending = 0;
+    }
+
+    Self {
+      text: Cow::Owned(text),
+      shifts,
+    }
+  }
+
+  /// Returns the span in the file of the characters of a span of the decoded text, since Kakoune selects bytes of the file.
+  pub fn original_span(&self, span: Span) -> Span {
+    if self.shifts.is_empty() {
+      return span;
+    }
+
+    // start columns are 1-based, and end columns are the 0-based columns following the span's last character.
+    let start = Loc::new(
+      span.start.line,
+      self.original_column(span.start.line - 1, span.start.column - 1) + 1,
+    );
+    let end = Loc::new(span.end.line, self.original_column(span.end.line - 1, span.end.column));
+
+    Span::new(start, end)
+  }
+
+  /// Returns the 0-based column in the file of a 0-based column of a line of the decoded text.
+  fn original_column(&self, line: usize, column: usize) -> usize {
+    let index = self.shifts.partition_point(|shift| (shift.line, shift.decoded) <= (line, column));
+
+    match index.checked_sub(1).map(|index| &self.shifts[index]) {
+      Some(shift) if shift.line == line => shift.original + column - shift.decoded,
+      _ => column,
+    }
+  }
+}
+
+impl Deref for Decoded<'_> {
+  type Target = str;
+
+  fn deref(&self) -> &str {
+    &self.text
+  }
+}
+
+impl Default for DetectionConfig {
+  fn default() -> Self {
+    Self {
+      shebangs: true,
+      modelines: true,
+      headers: true,
+      binary: true,
+      encoding: None,
+    }
+  }
+}
+
+fn deserialize_encoding<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'static Encoding>, D::Error> {
+  let label = String::deserialize(deserializer)?;
+
+  Encoding::for_label(label.as_bytes())
+    .map(Some)
+    .ok_or_else(|| D::Error::custom(format!("unknown encoding {label:?}")))
+}
+
+/// Returns whether `content` looks binary, which is the case if its first [`HEAD_LEN`] bytes contain a NUL byte.
+pub fn is_binary(content: &[u8]) -> bool {
+  content[..content.len().min(HEAD_LEN)].contains(&0)
+}
+
+/// Reads at most [`HEAD_LEN`] bytes from the start of the file at `path`.
+pub fn read_head<P: AsRef<Path>>(path: P) -> Option<Vec<u8>> {
+  let mut head = Vec::with_capacity(HEAD_LEN);
+
+  File::open(path).ok()?.take(HEAD_LEN as u64).read_to_end(&mut head).ok()?;
+
+  Some(head)
+}
+
+/// Returns the name of the interpreter of a shebang on the first line of `head`.
+///
+/// Interpreters run through `env` are resolved, and version suffixes are removed,
+/// so `#!/usr/bin/env python3.11` returns `python`.
+pub fn shebang(head: &str) -> Option<&str> {
+  let line = head.lines().next()?.strip_prefix("#!")?;
+  let mut args = line.split_whitespace().map(|arg| arg.rsplit('/').next().unwrap_or(arg));
+
+  let mut interpreter = args.next()?;
+
+  if interpreter == "env" {
+    interpreter = args.find(|arg| !arg.starts_with('-') && !arg.contains('='))?;
+  }
+
+  Some(interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.'))
+}
+
+/// Returns the filetype set by a Kakoune, Vim or Emacs modeline in the first lines of `head`.
+pub fn modeline(head: &str) -> Option<&str> {
+  head
+    .lines()
+    .take(MODELINE_LINES)
+    .find_map(|line| emacs_modeline(line).or_else(|| vim_modeline(line)))
+}
+
+/// Parses modelines such as `-*- mode: python -*-` and `-*- c++ -*-`.
+fn emacs_modeline(line: &str) -> Option<&str> {
+  let (_, rest) = line.split_once("-*-")?;
+  let (vars, _) = rest.split_once("-*-")?;
+
+  if !vars.contains(':') {
+    return Some(vars.trim()).filter(|mode| !mode.is_empty());
+  }
+
+  vars.split(';').find_map(|var| {
+    let (name, value) = var.split_once(':')?;
+
+    (name.trim().eq_ignore_ascii_case("mode")).then_some(value.trim())
+  })
+}
+
+/// Parses modelines such as `kak: filetype=python`, `vim: set ft=python :` and `vi: ft=python`.
+fn vim_modeline(line: &str) -> Option<&str> {
+  let options = ["kakoune:", "kak:", "vim:", "vi:", "ex:"].iter().find_map(|prefix| {
+    let start = line.match_indices(prefix).map(|(index, _)| index).find(|index| {
+      // the prefix must start a word, so `navi:` is not a modeline.
+      line[..*index].chars().next_back().is_none_or(char::is_whitespace)
+    })?;
+
+    Some(&line[start + prefix.len()..])
+  })?;
+
+  options.split([' ', '\t', ':']).find_map(|option| {
+    let (name, value) = option.split_once('=')?;
+
+    matches!(name, "ft" | "filetype").then_some(value).filter(|value| !value.is_empty())
+  })
+}
+
+/// Returns whether the start of a header contains C++ constructs.
+///
+/// Only lines starting with a construct that can't appear in C are considered, since
+/// C headers often contain C++ compatibility code such as `extern "C"` blocks.
+pub fn is_cpp_header(head: &str) -> bool {
+  head.lines().map(str::trim_start).any(|line| {
+    let word = line
+      .split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+      .next()
+      .unwrap_or_default();
+    let rest = line[word.len()..].trim_start();
+
+    // words such as `class` are valid identifiers in C, so they must be followed by what C++ expects.
+    let starts_with_identifier = rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+
+    match word {
+      "namespace" => starts_with_identifier || rest.starts_with('{'),
+      "template" => rest.starts_with('<'),
+      "class" => starts_with_identifier && !rest.contains(['=', '(']),
+      "public" | "private" | "protected" => rest.starts_with(':') && !rest.starts_with("::"),
+      "using" => rest.starts_with("namespace "),
+      _ => is_cpp_include(line),
+    }
+  })
+}
+
+/// Returns whether a line includes a C++ standard header, such as `#include <vector>`.
+fn is_cpp_include(line: &str) -> bool {
+  let Some(header) = line
+    .strip_prefix('#')
+    .map(str::trim_start)
+    .and_then(|line| line.strip_prefix("include"))
+  else {
+    return false;
+  };
+
+  let Some(header) = header.trim().strip_prefix('<').and_then(|header| header.strip_suffix('>')) else {
+    return false;
+  };
+
+  !header.contains(['.', '/'])
+}
+
+/// Returns the name of the configured language that an interpreter or filetype name refers to.
+pub fn language_name(name: &str) -> String {
+  let name = name.to_lowercase();
+
+  let language = match name.as_str() {
+    "c++" | "cxx" => "cpp",
+    "golang" => "go",
+    "hs" | "runghc" | "runhaskell" => "haskell",
+    "py" | "pypy" => "python",
+    "rs" => "rust",
+    "js" | "jsx" | "ts" | "tsx" | "javascript" | "node" | "nodejs" | "deno" | "bun" | "ts-node" => "typescript",
+    _ => return name,
+  };
+
+  language.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn shebangs() {
+    assert_eq!(shebang("#!/bin/bash\n"), Some("bash"));
+    assert_eq!(shebang("#!/usr/bin/env python3.11\n"), Some("python"));
+    assert_eq!(shebang("#!/usr/bin/env -S deno run --allow-read\n"), Some("deno"));
+    assert_eq!(shebang("#!/usr/bin/env PYTHONPATH=lib python3 -u\n"), Some("python"));
+    assert_eq!(shebang("#!/usr/bin/env -S\n"), None);
+    assert_eq!(shebang("print()\n#!/bin/bash\n"), None);
+  }
+
+  #[test]
+  fn modelines() {
+    assert_eq!(modeline("# kak: filetype=python\n"), Some("python"));
+    assert_eq!(modeline("// vim: set ts=2 ft=rust :\n"), Some("rust"));
+    assert_eq!(modeline("vi: ft=go\n"), Some("go"));
+    assert_eq!(modeline("/* -*- mode: C++; tab-width: 2 -*- */\n"), Some("C++"));
+    assert_eq!(modeline("# -*- haskell -*-\n"), Some("haskell"));
+    assert_eq!(modeline("# navi: ft=python\n"), None);
+    assert_eq!(modeline("# vim: set ts=2 :\n"), None);
+    assert_eq!(modeline("1\n2\n3\n4\n5\n# kak: filetype=python\n"), None);
+  }
+
+  #[test]
+  fn cpp_headers() {
+    assert!(is_cpp_header("#pragma once\nnamespace foo {\n"));
+    assert!(is_cpp_header("template <typename T>\nstruct vec;\n"));
+    assert!(is_cpp_header("class Foo : public Bar {\n"));
+    assert!(is_cpp_header("#include <vector>\n"));
+    assert!(is_cpp_header("  public:\n"));
+
+    assert!(!is_cpp_header("#ifdef __cplusplus\nextern \"C\" {\n#endif\nint foo(void);\n"));
+    assert!(!is_cpp_header("int class = 0;\nclass x = 1;\n"));
+    assert!(!is_cpp_header("#include <stdio.h>\n#include <sys/types.h>\n"));
+    assert!(!is_cpp_header("foo::bar();\nstd::public::x;\n"));
+  }
+
+  #[test]
+  fn decoded_spans() {
+    let span = |start: (usize, usize), end: (usize, usize)| Span::new(Loc::new(start.0, start.1), Loc::new(end.0, end.1));
+
+    let utf8 = DetectionConfig::default().decode("fn é() {}".as_bytes());
+    assert_eq!(utf8.original_span(span((1, 4), (1, 5))), span((1, 4), (1, 5)));
+
+    let lossy = DetectionConfig::default().decode(b"a\xe9b\xffc\n\xe9x");
+    assert_eq!(&*lossy, "a\u{fffd}b\u{fffd}c\n\u{fffd}x");
+    assert_eq!(lossy.original_span(span((1, 9), (1, 9))), span((1, 5), (1, 5)));
+    assert_eq!(lossy.original_span(span((1, 5), (1, 9))), span((1, 3), (1, 5)));
+    assert_eq!(lossy.original_span(span((2, 4), (2, 4))), span((2, 2), (2, 2)));
+
+    let detection = DetectionConfig {
+      encoding: Encoding::for_label(b"windows-1252"),
+      ..DetectionConfig::default()
+    };
+
+    let encoded = detection.decode(b"\xe9t\xe9 = 1\n");
+    assert_eq!(&*encoded, "\u{e9}t\u{e9} = 1\n");
+    assert_eq!(encoded.original_span(span((1, 1), (1, 5))), span((1, 1), (1, 3)));
+    assert_eq!(encoded.original_span(span((1, 9), (1, 9))), span((1, 7), (1, 7)));
+  }
+
+  #[test]
+  fn language_names() {
+    assert_eq!(language_name("c++"), "cpp");
+    assert_eq!(language_name("Python"), "python");
+    assert_eq!(language_name("node"), "typescript");
+    assert_eq!(language_name("bash"), "bash");
+  }
+}
@@ -12,17 +12,14 @@ use crossbeam::channel::Receiver;
 
 pub struct Fd {
   files: Receiver<PathBuf>,
-  handle: JoinHandle<()>,
+  handle: JoinHandle<Result<(), anyhow::Error>>,
 }
 
 impl Fd {
-  /// Spawns an fd process finding all files with the provided `extensions`.
-  pub fn new<'a>(extensions: impl IntoIterator<Item = &'a str>) -> Result<Self, anyhow::Error> {
-    let extension_args: Vec<&str> = extensions.into_iter().flat_map(|ext| vec!["-e", ext]).collect();
-
+  /// Spawns an fd process finding all files whose absolute path matches the regular expression `pattern`.
+  pub fn new(pattern: &str) -> Result<Self, anyhow::Error> {
     let mut child = Command::new("fd")
-      .args(["-t", "f", "-0"])
-      .args(extension_args)
+      .args(["-t", "f", "-0", "--full-path", pattern])
       .stdout(Stdio::piped())
       .spawn()
       .context("spawn")?;
@@ -33,10 +30,20 @@ impl Fd {
 
     let handle = std::thread::spawn(move || {
       for line in BufReader::new(stdout).split(b'\0') {
-        let line = OsString::from_vec(line.expect("failed to get line"));
+        let file = PathBuf::from(OsString::from_vec(line.context("failed to get line")?));
+
+        // all receivers are dropped if the walk is no longer needed.
+        if send.send(file).is_err() {
+          child.kill().context("kill")?;
 
-        send.send(PathBuf::from(line)).expect("failed to send");
+          anyhow::bail!("walk interrupted");
+        }
       }
+
+      let status = child.wait().context("wait")?;
+      anyhow::ensure!(status.success(), "fd exited with {status}");
+
+      Ok(())
     });
 
     Ok(Self { files: recv, handle })
@@ -46,4 +53,14 @@ impl Fd {
   pub fn files(&self) -> &Receiver<PathBuf> {
     &self.files
   }
+
+  /// Waits for the walk to end.
+  ///
+  /// Returns an error if the walk did not output every file, which is the case if all
+  /// receivers of [`Fd::files`] were dropped before the walk completed.
+  pub fn wait(self) -> Result<(), anyhow::Error> {
+    drop(self.files);
+
+    self.handle.join().map_err(|_| anyhow::anyhow!("fd thread panicked"))?
+  }
 }
@@ -12,13 +12,18 @@ use serde::{Deserialize, Serialize};
 
 const SPACE: char = '\u{2008}';
 
-use crate::{config::FzfSettings, symbol::Kind, text::Loc};
+use crate::{
+  config::FzfSettings,
+  symbol::{Kind, Symbol},
+  text::Span,
+};
 
 pub struct Fzf {
   child: Child,
   stdin: Arc<Mutex<ChildStdin>>,
 }
 
+#[derive(Clone)]
 pub struct Sink {
   stdin: Arc<Mutex<ChildStdin>>,
 }
@@ -26,21 +31,30 @@ pub struct Sink {
 #[derive(Serialize, Deserialize)]
 pub struct Entry<P, S> {
   pub path: P,
-  pub loc: Loc,
+  /// The span of the symbol's name.
+  pub span: Span,
   pub text: S,
+  pub qualified: Option<S>,
   pub kind: Kind,
+  /// The span of the symbol's full definition.
+  pub definition: Span,
+  pub context: Option<S>,
+  pub doc: Option<S>,
 }
 
 impl Fzf {
   /// Spawns `fzf` process that expects stdin entries of the form
-  /// `<path> <line> <column> <text> <kind>` separated by [`SPACE`].
+  /// `<path> <line> <column> <text> <kind> <context> <doc> <span> <definition>` separated by [`SPACE`].
+  ///
+  /// The text of qualified symbols is their qualified name, which contains their short name,
+  /// so either can be searched.
   pub fn new(settings: &FzfSettings) -> Result<Fzf, anyhow::Error> {
     let mut child = Command::new("fzf")
       .args([
         "--ansi",
         &format!("--delimiter={SPACE}"),
-        "--nth=-1",
-        "--with-nth=5,4",
+        "--nth=2",
+        "--with-nth=5,4,6,7",
         "--reverse",
         "--preview=bat {1} --color always --style=numbers,snip,header --highlight-line {2} --line-range {2}:+100",
         "--bind=tab:down,shift-tab:up",
@@ -63,19 +77,30 @@ impl Fzf {
     Sink::new(self.stdin.clone())
   }
 
-  pub fn wait(self) -> Result<String, anyhow::Error> {
+  /// Waits for `fzf` to exit, returning the selected entry, or `None` if the search was cancelled.
+  pub fn wait(self) -> Result<Option<String>, anyhow::Error> {
     // when all references to `stdin` are dropped, the spinner will stop.
     drop(self.stdin);
 
     let output = self.child.wait_with_output().context("wait")?;
 
-    let selection = String::from_utf8_lossy(&output.stdout)
-      .split(SPACE)
-      .take(3)
+    // the selection is `<path> <line> <column> <span> <definition>`, where spans are Kakoune selection descriptions.
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    if output.trim().is_empty() {
+      return Ok(None);
+    }
+
+    let fields: Vec<_> = output.split(SPACE).collect();
+
+    let selection = [0, 1, 2, 7, 8]
+      .iter()
+      .filter_map(|index| fields.get(*index))
+      .copied()
       .collect::<Vec<_>>()
       .join(" ");
 
-    Ok(selection)
+    Ok(Some(selection))
   }
 }
 
@@ -92,8 +117,50 @@ impl Sink {
 }
 
 impl<P, S> Entry<P, S> {
-  pub fn new(path: P, loc: Loc, text: S, kind: Kind) -> Self {
-    Self { path, loc, text, kind }
+  /// Returns this entry with another path, converting its strings with `string`.
+  pub fn map<Q, T>(self, path: Q, string: impl Fn(S) -> T) -> Entry<Q, T> {
+    Entry {
+      path,
+      span: self.span,
+      text: string(self.text),
+      qualified: self.qualified.map(&string),
+      kind: self.kind,
+      definition: self.definition,
+      context: self.context.map(&string),
+      doc: self.doc.map(&string),
+    }
+  }
+
+  /// Returns a borrowed copy of this entry at `path`.
+  pub fn at<Q>(&self, path: Q) -> Entry<Q, &str>
+  where
+    S: AsRef<str>,
+  {
+    Entry {
+      path,
+      span: self.span,
+      text: self.text.as_ref(),
+      qualified: self.qualified.as_ref().map(AsRef::as_ref),
+      kind: self.kind,
+      definition: self.definition,
+      context: self.context.as_ref().map(AsRef::as_ref),
+      doc: self.doc.as_ref().map(AsRef::as_ref),
+    }
+  }
+}
+
+impl<'a, P> Entry<P, &'a str> {
+  pub fn from_symbol(path: P, symbol: &'a Symbol) -> Self {
+    Self {
+      path,
+      span: symbol.span,
+      text: &symbol.text,
+      qualified: symbol.qualified.as_deref(),
+      kind: symbol.kind,
+      definition: symbol.definition,
+      context: symbol.context.as_deref(),
+      doc: symbol.doc.as_deref(),
+    }
   }
 }
 
@@ -101,12 +168,28 @@ impl<P: AsRef<Path>, S: Display> Display for Entry<P, S> {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     write!(
       f,
-      "{path}{SPACE}{line}{SPACE}{column}{SPACE}{text}{SPACE}{kind}{SPACE}",
+      "{path}{SPACE}{line}{SPACE}{column}{SPACE}{text}{SPACE}{kind}{SPACE}{context}{SPACE}{doc}{SPACE}{span}{SPACE}{definition}{SPACE}",
       path = self.path.as_ref().to_string_lossy(),
-      line = self.loc.line,
-      column = self.loc.column,
-      text = self.text,
+      line = self.span.start.line,
+      column = self.span.start.column,
+      text = self.qualified.as_ref().unwrap_or(&self.text),
       kind = self.kind.colored_abbreviation(),
+      context = Dim(self.context.as_ref()),
+      doc = Dim(self.doc.as_ref()),
+      span = self.span,
+      definition = self.definition,
     )
   }
 }
+
+/// An optional field of an [`Entry`], displayed dimmed, or empty if it is missing.
+struct Dim<T>(Option<T>);
+
+impl<T: Display> Display for Dim<T> {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match &self.0 {
+      Some(value) => write!(f, "\x1b[2m{value}\x1b[0m"),
+      None => Ok(()),
+    }
+  }
+}
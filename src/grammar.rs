@@ -0,0 +1,68 @@
+use std::{os::unix::ffi::OsStrExt, path::PathBuf};
+
+use anyhow::Context;
+use libloading::Library;
+use serde::Deserialize;
+use tree_sitter::{Language as TreeSitterLanguage, LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
+use tree_sitter_language::LanguageFn;
+use xxhash_rust::xxh3::Xxh3;
+
+/// A tree-sitter grammar loaded at runtime from a shared library, such as the ones built by Helix or Neovim.
+#[derive(Deserialize)]
+pub struct GrammarConfig {
+  /// The path of the shared library.
+  pub library: PathBuf,
+  /// The name of the library's function returning the grammar. Defaults to `tree_sitter_<language>`.
+  pub symbol: Option<String>,
+  /// The extensions of files using this grammar.
+  #[serde(default)]
+  pub extensions: Vec<String>,
+}
+
+impl GrammarConfig {
+  /// Loads the grammar of the language `name`.
+  ///
+  /// The library is never unloaded, as the grammar is used for the lifetime of the configuration.
+  pub fn load(&self, name: &str) -> Result<TreeSitterLanguage, anyhow::Error> {
+    let symbol = match &self.symbol {
+      Some(symbol) => symbol.clone(),
+      None => format!("tree_sitter_{}", name.replace('-', "_")),
+    };
+
+    // SAFETY: loading a library runs its initialization routines. Grammars are trusted
+    // libraries generated by the tree-sitter CLI, which have none, as they are only configured by
+    // the user's configuration or `--config`, never by a project's configuration file.
+    let library = unsafe { Library::new(&self.library) }.with_context(|| format!("failed to load {:?}", self.library))?;
+
+    // SAFETY: functions generated by the tree-sitter CLI have this signature.
+    let language_fn = unsafe { library.get::<unsafe extern "C" fn() -> *const ()>(symbol.as_bytes()) }
+      .with_context(|| format!("failed to find {symbol} in {:?}", self.library))?;
+
+    // SAFETY: same as above.
+    let language = TreeSitterLanguage::new(unsafe { LanguageFn::from_raw(*language_fn) });
+
+    std::mem::forget(library);
+
+    let version = language.version();
+
+    anyhow::ensure!(
+      (MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version),
+      "grammar {name} has ABI version {version}, but only versions {MIN_COMPATIBLE_LANGUAGE_VERSION} to {LANGUAGE_VERSION} are supported",
+    );
+
+    Ok(language)
+  }
+
+  /// Hashes the identity of the library, so cached symbols are invalidated when it is rebuilt.
+  pub fn hash(&self, hasher: &mut Xxh3) {
+    hasher.update(self.library.as_os_str().as_bytes());
+
+    if let Ok(metadata) = std::fs::metadata(&self.library) {
+      hasher.update(&metadata.len().to_le_bytes());
+
+      if let Ok(modified) = metadata.modified() {
+        hasher.update(format!("{modified:?}").as_bytes());
+      }
+    }
+  }
+}
@@ -0,0 +1,144 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+/// Limits skipping files that are slow to index and whose symbols are rarely searched, such as
+/// generated or minified files. A limit of zero is disabled.
+#[derive(Clone, Copy, Deserialize)]
+pub struct Limits {
+  /// The maximum size of files, in bytes.
+  #[serde(default = "Limits::default_max_file_size")]
+  pub max_file_size: u64,
+  /// The maximum length of the lines of files, in bytes. Files with longer lines are likely minified.
+  #[serde(default = "Limits::default_max_line_length")]
+  pub max_line_length: usize,
+  /// The maximum time spent parsing a file, in milliseconds.
+  #[serde(default = "Limits::default_parse_timeout")]
+  pub parse_timeout: u64,
+  /// The maximum number of symbols of a file.
+  #[serde(default = "Limits::default_max_symbols")]
+  pub max_symbols: usize,
+}
+
+/// A limit exceeded by a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Limit {
+  FileSize,
+  LineLength,
+  ParseTimeout,
+  Symbols,
+}
+
+/// A limit exceeded by a file, along with the limit's value at the time.
+///
+/// The file exceeds any lower value of the limit as well, so it is only checked again if the limit
+/// is raised or disabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exceeded {
+  pub limit: Limit,
+  pub max: u64,
+}
+
+impl Limits {
+  fn default_max_file_size() -> u64 {
+    1024 * 1024
+  }
+
+  fn default_max_line_length() -> usize {
+    4096
+  }
+
+  fn default_parse_timeout() -> u64 {
+    1000
+  }
+
+  fn default_max_symbols() -> usize {
+    10_000
+  }
+
+  /// Returns the limit exceeded by a file of `size` bytes, if any.
+  pub fn check_size(&self, size: u64) -> Option<Limit> {
+    (self.max_file_size != 0 && size > self.max_file_size).then_some(Limit::FileSize)
+  }
+
+  /// Returns the limit exceeded by the lines of `content`, if any.
+  pub fn check_lines(&self, content: &[u8]) -> Option<Limit> {
+    let max = self.max_line_length;
+
+    (max != 0 && content.split(|byte| *byte == b'\n').any(|line| line.len() > max)).then_some(Limit::LineLength)
+  }
+
+  /// Returns the limit exceeded by a file with `count` symbols, if any.
+  pub fn check_symbols(&self, count: usize) -> Option<Limit> {
+    (self.max_symbols != 0 && count > self.max_symbols).then_some(Limit::Symbols)
+  }
+
+  /// Returns the parse timeout in microseconds, or zero if parsing is not limited.
+  pub fn parse_timeout_micros(&self) -> u64 {
+    self.parse_timeout.saturating_mul(1000)
+  }
+
+  /// Returns the current value of a limit.
+  fn max(&self, limit: Limit) -> u64 {
+    match limit {
+      Limit::FileSize => self.max_file_size,
+      Limit::LineLength => self.max_line_length as u64,
+      Limit::ParseTimeout => self.parse_timeout,
+      Limit::Symbols => self.max_symbols as u64,
+    }
+  }
+
+  /// Returns a limit exceeded by a file, along with its current value.
+  pub fn exceeded(&self, limit: Limit) -> Exceeded {
+    Exceeded {
+      limit,
+      max: self.max(limit),
+    }
+  }
+
+  /// Returns whether a file that exceeded a limit still exceeds it, which is the case if the limit
+  /// is enabled and was not raised since.
+  pub fn still_exceeded(&self, exceeded: Exceeded) -> bool {
+    let max = self.max(exceeded.limit);
+
+    max != 0 && max <= exceeded.max
+  }
+}
+
+impl Default for Limits {
+  fn default() -> Self {
+    Self {
+      max_file_size: Self::default_max_file_size(),
+      max_line_length: Self::default_max_line_length(),
+      parse_timeout: Self::default_parse_timeout(),
+      max_symbols: Self::default_max_symbols(),
+    }
+  }
+}
+
+impl Limit {
+  /// Returns a short description of why a file exceeding this limit was skipped.
+  pub fn reason(&self) -> &'static str {
+    match self {
+      Self::FileSize => "too large",
+      Self::LineLength => "minified",
+      Self::ParseTimeout => "timed out",
+      Self::Symbols => "too many symbols",
+    }
+  }
+}
+
+impl Display for Limit {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let limit = match self {
+      Self::FileSize => "max_file_size",
+      Self::LineLength => "max_line_length",
+      Self::ParseTimeout => "parse_timeout",
+      Self::Symbols => "max_symbols",
+    };
+
+    write!(f, "{} (exceeds {limit})", self.reason())
+  }
+}
+
+impl std::error::Error for Limit {}
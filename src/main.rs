@@ -1,58 +1,112 @@
 mod cache;
 mod config;
+mod detect;
 mod ext;
 mod fd;
 mod fzf;
+mod grammar;
+mod limits;
 mod parser;
+mod root;
 mod symbol;
 mod text;
 mod utils;
 mod worker;
 
-use std::path::PathBuf;
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
-use crate::{cache::Cache, config::Config, fd::Fd, fzf::Fzf, worker::Worker};
+use crate::{
+  cache::Cache,
+  config::Config,
+  ext::ResultExt,
+  fd::Fd,
+  fzf::Fzf,
+  worker::{Skipped, Worker},
+};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+  #[command(subcommand)]
+  command: Option<Command>,
   /// A configuration TOML string.
   ///
-  /// The default configuration will be applied if this argument is not provided
-  /// or if it is set to the empty string.
-  #[arg(short, long)]
+  /// The configuration is merged from the default configuration, the user's configuration
+  /// file (`$XDG_CONFIG_HOME/kak-symbol-search/config.toml`), the project's `.symbol-search.toml`
+  /// and this argument, each overriding the languages, kinds and settings of the previous ones.
+  #[arg(short, long, global = true)]
   config: Option<String>,
   /// Directory to cache parsed symbols.
   ///
-  /// Files are reparsed if their cached mtime differs from than their current mtime.
-  /// The cache is only usable if the previously generated relative paths are still valid.
-  /// This would normally only be the case when the binary is called from the same
-  /// directory multiple times.
+  /// Files are reparsed if their size or content hash differs from their cached ones.
+  /// The content is only hashed when the size matches but the mtime differs.
+  /// One cache is kept per project root, which is the closest ancestor of the current
+  /// directory containing a VCS directory (e.g. `.git`) or a `.symbol-search.toml` file.
+  /// Files are cached by their canonical path relative to the project root, so the cache
+  /// is shared when the binary is called from any directory inside the project.
+  /// The same directory can be used by concurrent processes.
   ///
   /// This directory is created if it does not exist.
-  #[arg(short, long)]
+  #[arg(short = 'd', long, global = true)]
   cache_dir: Option<PathBuf>,
+  /// Also remove cached files that are not indexed with the current configuration.
+  ///
+  /// Cached files that no longer exist are always removed once a walk of the current
+  /// directory completes. With this flag, files anywhere in the project whose language
+  /// is not indexed by the current configuration are removed as well.
+  #[arg(long)]
+  cache_gc: bool,
+  /// List each skipped file and why it was skipped, rather than only the number of files skipped for each reason.
+  #[arg(short, long)]
+  verbose: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+  /// Inspect or clear the cache of the current project.
+  Cache {
+    #[command(subcommand)]
+    command: CacheCommand,
+  },
+  /// Validate the configuration and print its languages, without searching for symbols.
+  CheckConfig,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+  /// Print the number of cached files and symbols, and how many files are stale.
+  Stats,
+  /// Re-parse a sample of cached files and print differences with their cached symbols.
+  Verify {
+    /// The maximum number of files to re-parse.
+    #[arg(short, long, default_value_t = 100)]
+    sample: usize,
+  },
+  /// Print the cached symbols of a file.
+  List { path: PathBuf },
+  /// Remove the cache of the current project.
+  Clear,
 }
 
 impl Args {
-  /// Returns the parsed provided config or the default one.
-  pub fn config(&self) -> Result<Config, anyhow::Error> {
-    if let Some(config) = &self.config {
-      if !config.is_empty() {
-        return toml::from_str(config).context("from_str");
-      }
-    }
+  /// Returns the configuration of the project at `root`, overridden by the provided config.
+  pub fn config(&self, root: &Path) -> Result<Config, anyhow::Error> {
+    let config = self.config.as_deref().filter(|config| !config.is_empty());
 
-    Ok(Config::default())
+    Ok(Config::load(Some(root), config)?)
   }
 
-  /// Returns the provided cache or an empty one.
-  pub fn cache(&self) -> Result<Cache, anyhow::Error> {
+  /// Returns the provided cache of the project at `root`, or an empty one.
+  pub fn cache(&self, root: PathBuf) -> Result<Cache, anyhow::Error> {
     if let Some(cache_dir) = &self.cache_dir {
-      Cache::from_dir(cache_dir).context("from_str")
+      Cache::from_dir(cache_dir, root).context("from_dir")
     } else {
       Ok(Cache::default())
     }
@@ -62,23 +116,103 @@ impl Args {
 fn main() -> Result<(), anyhow::Error> {
   let args = Args::parse();
 
-  let config = Box::new(args.config().context("config")?);
+  let root = crate::root::find_root(".").context("find_root")?;
+
+  let config = Box::new(args.config(&root).context("config")?);
   let config: &'static Config = Box::leak(config);
 
-  let cache = args.cache().context("cache")?;
+  if let Some(Command::CheckConfig) = &args.command {
+    config.print_summary();
+
+    return Ok(());
+  }
+
+  let cache = args.cache(root.clone()).context("cache")?;
+
+  if let Some(Command::Cache { command }) = &args.command {
+    anyhow::ensure!(args.cache_dir.is_some(), "--cache-dir is required");
+
+    return match command {
+      CacheCommand::Stats => cache.print_stats(config),
+      CacheCommand::Verify { sample } => cache.verify(config, *sample),
+      CacheCommand::List { path } => cache.list(path),
+      CacheCommand::Clear => cache.clear(),
+    };
+  }
 
   let fzf = Fzf::new(&config.fzf_settings).context("fzf")?;
-  let fd = Fd::new(config.extensions()).context("fd")?;
+  let dir = std::env::current_dir().context("current dir")?;
+  let fd = Fd::new(&config.file_pattern(&root)).context("fd")?;
+
+  let skipped = Skipped::default();
+  let scope = dir.strip_prefix(&root).context("scope")?;
+  let worker = Worker::new(config, &cache, scope, fd.files(), &fzf, &skipped);
+
+  // cached files are listed in order first, while fd walks, and other files are listed as they are parsed.
+  // if fzf exits while they are listed, the workers stop as well.
+  let sent = match worker.send_cached(&dir, config.fzf_settings.order).context("send cached") {
+    Ok(sent) => sent,
+    Err(err) => {
+      if !is_broken_pipe(&err) {
+        eprintln!("{err:?}");
+      }
 
-  for _ in 0..crate::utils::num_threads() {
-    Worker::new(config, &cache, fd.files(), &fzf).run();
+      HashSet::new()
+    }
+  };
+
+  let sent = Arc::new(sent);
+
+  let workers: Vec<_> = (0..crate::utils::num_threads()).map(|_| worker.clone().run(&sent)).collect();
+
+  // fzf's input is only closed once every worker's sink is dropped.
+  drop(worker);
+
+  // nothing is printed if the search was cancelled, so no symbol is jumped to.
+  if let Some(selection) = fzf.wait().context("wait")? {
+    println!("{selection}");
   }
 
-  // the cache is saved on drop
-  drop(cache);
+  // workers stop once fzf exits, so the walk may be incomplete.
+  let mut complete = true;
+
+  for worker in workers {
+    match worker.join() {
+      Ok(Ok(())) => {}
+      Ok(Err(err)) => {
+        complete = false;
+
+        if !is_broken_pipe(&err) {
+          eprintln!("{err:?}");
+        }
+      }
+      Err(_) => complete = false,
+    }
+  }
 
-  let selection = fzf.wait().context("wait")?;
-  println!("{selection}");
+  complete &= fd.wait().is_ok();
+
+  skipped.report(args.verbose);
+
+  if complete {
+    cache.prune(".");
+  }
+
+  if args.cache_gc {
+    let root = cache.root().map(Path::to_path_buf).unwrap_or_default();
+
+    cache.retain(|key| config.detect_language(key, || None, || detect::read_head(root.join(key))).is_some());
+  }
+
+  cache.save().context("save cache").warn();
 
   Ok(())
 }
+
+/// Returns whether an error was caused by writing to an exited process.
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+  err
+    .chain()
+    .filter_map(|err| err.downcast_ref::<std::io::Error>())
+    .any(|err| err.kind() == std::io::ErrorKind::BrokenPipe)
+}
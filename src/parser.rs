@@ -1,75 +1,223 @@
-use std::{collections::HashSet, path::{Path, PathBuf}};
+use std::{
+  borrow::Cow,
+  collections::{hash_map::Entry, BTreeMap, HashMap},
+  path::Path,
+};
 
 use anyhow::Context;
 use streaming_iterator::StreamingIterator;
-use tree_sitter::{Parser as TreeSitterParser, QueryCursor};
+use tree_sitter::{Node, Parser as TreeSitterParser, QueryCursor, Tree};
 
 use crate::{
   config::{Config, Language, LanguageConfig},
+  limits::{Limit, Limits},
   symbol::Symbol,
-  text::{Loc, Span},
+  text::{self, Loc, Span},
 };
 
 pub struct Parser<'a> {
-  path: PathBuf,
   language: Language,
   language_config: &'a LanguageConfig,
+  limits: &'a Limits,
+}
+
+/// The tree-sitter parsers and query cursor of a thread, which are reused across the files it parses.
+#[derive(Default)]
+pub struct ParseState {
+  parsers: HashMap<Language, TreeSitterParser>,
+  cursor: QueryCursor,
 }
 
 impl<'a> Parser<'a> {
-  pub fn from_path<P: AsRef<Path>>(config: &'a Config, path: P) -> Option<Self> {
-    let path = path.as_ref();
-    let extension = path.extension()?.to_str()?;
-    let language = Language::from_extension(extension)?;
+  /// Returns the parser of the file at `path`, if its language is configured.
+  ///
+  /// `cached` returns the name of the language previously detected from the file's content, and `head`
+  /// returns the start of the file, which are used if its language is detected from its content.
+  pub fn from_path<P: AsRef<Path>>(
+    config: &'a Config,
+    path: P,
+    cached: impl FnOnce() -> Option<String>,
+    head: impl FnOnce() -> Option<Vec<u8>>,
+  ) -> Option<Self> {
+    let language = config.detect_language(path, cached, head)?;
     let language_config = config.languages.get(&language)?;
 
     Some(Self {
-      path: path.to_path_buf(),
       language,
       language_config,
+      limits: config.limits(),
     })
   }
 
-  pub fn on_symbol(&self, callback: impl Fn(Symbol) -> Result<(), anyhow::Error>) -> Result<(), anyhow::Error> {
-    let mut parser = TreeSitterParser::new();
-    parser.set_language(&self.language.to_tree_sitter()).context("set_language")?;
+  /// Returns the language of this parser.
+  pub fn language(&self) -> Language {
+    self.language
+  }
 
-    let content = std::fs::read_to_string(&self.path).context("read")?;
+  /// Returns the fingerprint of the configuration used by this parser.
+  pub fn fingerprint(&self) -> u64 {
+    self.language_config.fingerprint
+  }
 
-    let tree = parser.parse(content.as_bytes(), None).context("parse")?;
-    let mut positions = HashSet::new();
+  /// Parses `content`.
+  ///
+  /// Returns a [`Limit`] error if parsing takes longer than the parse timeout.
+  pub fn parse(&self, state: &mut ParseState, content: &str) -> Result<Tree, anyhow::Error> {
+    let parser = match state.parsers.entry(self.language) {
+      Entry::Occupied(entry) => entry.into_mut(),
+      Entry::Vacant(entry) => {
+        let mut parser = TreeSitterParser::new();
+        parser.set_language(&self.language_config.grammar).context("set_language")?;
+
+        entry.insert(parser)
+      }
+    };
 
-    for (kind, queries) in &self.language_config.symbol_queries {
+    let timeout = self.limits.parse_timeout_micros();
 
-      for query in queries {
-        let mut cursor = QueryCursor::new();
-        let mut matches = cursor.matches(query, tree.root_node(), content.as_bytes());
+    parser.set_timeout_micros(timeout);
 
-        while let Some(m) = matches.next() {
-          for capture in m.captures {
-            let node = capture.node;
-            let start_pos = node.start_position();
+    match parser.parse(content.as_bytes(), None) {
+      Some(tree) => Ok(tree),
+      None if timeout != 0 => {
+        // a parser that timed out resumes parsing on its next call unless it is reset.
+        parser.reset();
 
-            if positions.contains(&start_pos) {
-              continue;
-            } else {
-              positions.insert(start_pos);
-            }
+        Err(Limit::ParseTimeout.into())
+      }
+      None => anyhow::bail!("parse"),
+    }
+  }
 
-            let end_pos = node.start_position();
+  /// Calls `callback` with the symbols of `tree`, by position.
+  ///
+  /// Returns a [`Limit`] error without calling `callback` if the file has too many symbols.
+  pub fn on_symbol(
+    &self,
+    state: &mut ParseState,
+    content: &str,
+    tree: &Tree,
+    mut callback: impl FnMut(Symbol) -> Result<(), anyhow::Error>,
+  ) -> Result<(), anyhow::Error> {
+    // symbols by position, along with their rank. symbols at the same position are de-duplicated by
+    // keeping the one whose kind has the highest priority, or the one from the first query if their kinds are the same.
+    let mut symbols = BTreeMap::new();
+    let scopes = self.scopes(state, content, tree);
+
+    let queries = &self.language_config.queries;
+    let mut matches = state.cursor.matches(&queries.query, tree.root_node(), content.as_bytes());
+
+    while let Some(m) = matches.next() {
+      let (index, query) = queries.query_of(m);
+
+      for symbol in query.symbols(m) {
+        let node = symbol.name;
+        let start_pos = node.start_position();
+        let rank = (self.language_config.rank(symbol.kind), index);
+
+        if symbols.get(&start_pos).is_some_and(|(existing, _)| *existing <= rank) {
+          continue;
+        }
 
-            let start_byte = node.start_byte();
-            let end_byte = node.end_byte();
-            let text = &content[start_byte..end_byte];
+        let start_byte = node.start_byte();
+        let end_byte = node.end_byte();
+        let text = match query.format(m, content) {
+          Some(text) => Cow::Owned(text),
+          None => Cow::Borrowed(&content[start_byte..end_byte]),
+        };
+
+        let span = node_span(node, content);
+        let definition = symbol.definition.or_else(|| node.parent()).unwrap_or(node);
+
+        let symbol = Symbol {
+          span,
+          qualified: self.qualified_name(&scopes, node, &text),
+          text,
+          kind: symbol.kind,
+          definition: node_span(definition, content),
+          context: symbol.context.map(|node| text::single_line(&content[node.byte_range()])),
+          doc: symbol.doc.and_then(|node| text::doc_summary(&content[node.byte_range()])),
+        };
+
+        symbols.insert(start_pos, (rank, symbol));
+      }
+    }
 
-            let span = Span::new(Loc::new(start_pos.row + 1, start_pos.column + 1), Loc::new(end_pos.row + 1, end_pos.column + 1));
+    if let Some(limit) = self.limits.check_symbols(symbols.len()) {
+      return Err(limit.into());
+    }
 
-            callback(Symbol { span, text, kind: *kind }).context("callback")?;
-          }
-        }
-      }
+    for (_, symbol) in symbols.into_values() {
+      callback(symbol).context("callback")?;
     }
 
     Ok(())
   }
+
+  /// Returns the names of the scopes of `tree`, by node id.
+  ///
+  /// If several scope queries match the same node, the name from the first query is used.
+  fn scopes(&self, state: &mut ParseState, content: &str, tree: &Tree) -> HashMap<usize, String> {
+    let mut scopes = HashMap::<usize, (usize, String)>::new();
+
+    let Some(query) = &self.language_config.scopes.query else {
+      return HashMap::new();
+    };
+
+    let mut matches = state.cursor.matches(query, tree.root_node(), content.as_bytes());
+
+    while let Some(m) = matches.next() {
+      let Some((index, node, name)) = self.language_config.scopes.scope(m, content) else {
+        continue;
+      };
+
+      if scopes.get(&node.id()).is_none_or(|(existing, _)| index < *existing) {
+        scopes.insert(node.id(), (index, name));
+      }
+    }
+
+    scopes.into_iter().map(|(id, (_, name))| (id, name)).collect()
+  }
+
+  /// Returns the name of the symbol at `node` qualified by the names of its enclosing scopes, if it has any.
+  ///
+  /// The node itself can be a scope, such as the name of a qualified identifier like `Class::method`.
+  fn qualified_name(&self, scopes: &HashMap<usize, String>, node: Node, text: &str) -> Option<String> {
+    let mut names = Vec::new();
+    let mut ancestor = Some(node);
+
+    while let Some(node) = ancestor {
+      names.extend(scopes.get(&node.id()).map(String::as_str));
+      ancestor = node.parent();
+    }
+
+    if names.is_empty() {
+      return None;
+    }
+
+    names.reverse();
+    names.push(text);
+
+    Some(names.join(&self.language_config.scopes.separator))
+  }
+}
+
+/// Returns the span of a node, from its first character to its last character.
+fn node_span(node: Node, content: &str) -> Span {
+  let (start, end) = (node.start_position(), node.end_position());
+
+  // the end position of a node is exclusive, so its 0-based column is the 1-based column of its last character.
+  // nodes ending with a newline end at column 0 of the next line, so they end at that newline instead.
+  let end = match end.column {
+    0 if node.end_byte() > node.start_byte() => {
+      let newline = node.end_byte() - 1;
+      let line_start = content[..newline].rfind('\n').map_or(0, |index| index + 1);
+
+      Loc::new(end.row, newline - line_start + 1)
+    }
+
+    column => Loc::new(end.row + 1, column.max(1)),
+  };
+
+  Span::new(Loc::new(start.row + 1, start.column + 1), end)
 }
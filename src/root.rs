@@ -0,0 +1,48 @@
+use std::{
+  os::unix::ffi::OsStrExt,
+  path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// The name of a project's configuration file, at the project's root.
+pub const PROJECT_CONFIG_FILE_NAME: &str = ".symbol-search.toml";
+
+/// Files or directories whose presence marks the root of a project.
+const ROOT_MARKERS: &[&str] = &[".git", ".hg", ".jj", ".svn", "_darcs", PROJECT_CONFIG_FILE_NAME];
+
+/// Returns the canonical project root containing `dir`.
+///
+/// The root is the closest ancestor of `dir` (including itself) containing one of the
+/// [`ROOT_MARKERS`]. If no ancestor contains a marker, `dir` itself is the root.
+pub fn find_root<P: AsRef<Path>>(dir: P) -> Result<PathBuf, anyhow::Error> {
+  let dir = std::fs::canonicalize(dir).context("canonicalize")?;
+
+  let root = dir
+    .ancestors()
+    .find(|ancestor| ROOT_MARKERS.iter().any(|marker| ancestor.join(marker).exists()))
+    .unwrap_or(&dir);
+
+  Ok(root.to_path_buf())
+}
+
+/// The maximum number of characters of a root's name kept in its [`dir_name`].
+const DIR_NAME_LEN: usize = 64;
+
+/// Returns a file name uniquely identifying the project at `root`, for use inside a cache directory.
+///
+/// The name is the hash of the root's path, preceded by the root's own name so cache directories
+/// can be told apart, which keeps it below file name length limits however deep the root is.
+pub fn dir_name(root: &Path) -> String {
+  let name: String = root
+    .file_name()
+    .unwrap_or_default()
+    .to_string_lossy()
+    .chars()
+    .filter(|char| char.is_alphanumeric() || matches!(char, '-' | '_' | '.'))
+    .take(DIR_NAME_LEN)
+    .collect();
+
+  format!("{name}-{:016x}", xxh3_64(root.as_os_str().as_bytes()))
+}
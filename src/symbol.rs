@@ -1,14 +1,26 @@
+use std::{borrow::Cow, fmt::Display};
+
 use serde::{Deserialize, Serialize};
 
 use crate::text::Span;
 
 pub struct Symbol<'a> {
   pub span: Span,
-  pub text: &'a str,
+  /// The text of the symbol's name, or the expansion of its query's template.
+  pub text: Cow<'a, str>,
+  /// The text qualified by the names of the symbol's enclosing scopes, such as `Class::method`.
+  pub qualified: Option<String>,
   pub kind: Kind,
+  /// The span of the symbol's full definition, which is its `@definition` capture, or the
+  /// parent of its name if its query has none.
+  pub definition: Span,
+  /// The name of the scope enclosing the symbol, on a single line.
+  pub context: Option<String>,
+  /// The first line of the symbol's documentation, without comment delimiters.
+  pub doc: Option<String>,
 }
 
-#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Kind {
   Module,
@@ -35,6 +47,30 @@ pub enum Kind {
 }
 
 impl Kind {
+  /// The kinds from highest to lowest priority, which is used when symbols of several kinds are at the
+  /// same position, unless a language configures its own priority.
+  ///
+  /// More specific kinds have a higher priority, such as methods, which are also matched as functions.
+  pub const PRIORITY: [Self; 17] = [
+    Self::Method,
+    Self::Function,
+    Self::Impl,
+    Self::Module,
+    Self::Macro,
+    Self::Class,
+    Self::Struct,
+    Self::Enum,
+    Self::Union,
+    Self::Interface,
+    Self::Trait,
+    Self::Alias,
+    Self::Type,
+    Self::Constant,
+    Self::Define,
+    Self::Global,
+    Self::Unknown,
+  ];
+
   #[rustfmt::skip]
   pub fn colored_abbreviation(self) -> &'static str {
     // these strings must all have the same printable length
@@ -62,3 +98,9 @@ impl Kind {
       Self::Unknown   => "\x1b[31m(??????)\x1b[0m",    }
   }
 }
+
+impl Display for Kind {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.pad(&format!("{self:?}").to_lowercase())
+  }
+}
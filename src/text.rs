@@ -1,3 +1,5 @@
+use std::fmt::Display;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -12,7 +14,7 @@ impl Loc {
   }
 }
 
-#[derive(Clone, Copy, Debug,PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Span {
   pub start: Loc,
   pub end: Loc,
@@ -23,3 +25,37 @@ impl Span {
     Self { start, end }
   }
 }
+
+/// Displays the span as a Kakoune selection description, such as `1.1,3.2`.
+impl Display for Span {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{},{}.{}", self.start.line, self.start.column, self.end.line, self.end.column)
+  }
+}
+
+/// Returns `text` on a single line, with each run of whitespace replaced by a single space.
+pub fn single_line(text: &str) -> String {
+  text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the first non-empty line of a documentation comment, without its comment delimiters.
+pub fn doc_summary(doc: &str) -> Option<String> {
+  const OPENERS: &[&str] = &["///", "//!", "//", "/**", "/*!", "/*", "*", "#", "--", "\"\"\"", "'''"];
+  const CLOSERS: &[&str] = &["*/", "\"\"\"", "'''"];
+
+  doc.lines().find_map(|line| {
+    let mut line = line.trim();
+
+    if let Some(closer) = CLOSERS.iter().find(|closer| line.ends_with(**closer)) {
+      line = &line[..line.len() - closer.len()];
+    }
+
+    if let Some(opener) = OPENERS.iter().find(|opener| line.starts_with(**opener)) {
+      line = &line[opener.len()..];
+    }
+
+    let line = single_line(line);
+
+    (!line.is_empty()).then_some(line)
+  })
+}
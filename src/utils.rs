@@ -15,6 +15,15 @@ pub enum OneOrMany<T> {
   Many(Vec<T>),
 }
 
+impl<T> OneOrMany<T> {
+  pub fn as_slice(&self) -> &[T] {
+    match self {
+      OneOrMany::One(val) => std::slice::from_ref(val),
+      OneOrMany::Many(vec) => vec,
+    }
+  }
+}
+
 impl<T> From<OneOrMany<T>> for Vec<T> {
   fn from(from: OneOrMany<T>) -> Self {
     match from {
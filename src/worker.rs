@@ -1,82 +1,424 @@
-use std::{path::PathBuf, thread::JoinHandle, time::SystemTime};
+use std::{
+  collections::{BTreeMap, HashSet},
+  fmt::Display,
+  path::{Component, Path, PathBuf},
+  sync::Arc,
+  thread::JoinHandle,
+  time::SystemTime,
+};
 
 use anyhow::Context;
 use crossbeam::channel::Receiver;
+use once_cell::unsync::OnceCell;
+use parking_lot::Mutex;
 
 use crate::{
-  cache::Cache,
-  config::Config,
+  cache::{content_hash, Cache, FileInfo},
+  config::{Config, Order},
+  detect,
   fzf::{Entry, Fzf, Sink},
-  parser::Parser,
+  limits::{Exceeded, Limit},
+  parser::{ParseState, Parser},
 };
 
+#[derive(Clone)]
 pub struct Worker {
   config: &'static Config,
   cache: Cache,
+  /// The walked directory, relative to the project root.
+  scope: PathBuf,
   files: Receiver<PathBuf>,
   fzf: Sink,
+  skipped: Skipped,
+  /// The keys of the cached files whose entries were sent before the walk.
+  sent: Arc<HashSet<PathBuf>>,
+}
+
+/// The files skipped by workers, which are reported once indexing ends.
+#[derive(Clone, Default)]
+pub struct Skipped(Arc<Mutex<Vec<(PathBuf, Skip)>>>);
+
+/// Why a file was skipped.
+pub enum Skip {
+  /// The file could not be read, such as a file deleted during the walk.
+  Unreadable(std::io::Error),
+  /// The file looks binary.
+  Binary,
+  /// The file could not be parsed.
+  Unparsable(anyhow::Error),
+  /// The file exceeds a limit, such as generated or minified files.
+  Limit(Limit),
+}
+
+/// A file being processed by a [`Worker`].
+///
+/// The file's content is only read when it is needed.
+struct File<'a> {
+  path: &'a PathBuf,
+  key: Option<PathBuf>,
+  modified: SystemTime,
+  size: u64,
+  parser: Parser<'static>,
+  /// Whether the file's language was detected from its content.
+  detected: bool,
+  content: OnceCell<Vec<u8>>,
 }
 
 impl Worker {
-  pub fn new(config: &'static Config, cache: &Cache, files: &Receiver<PathBuf>, fzf: &Fzf) -> Self {
+  pub fn new(config: &'static Config, cache: &Cache, scope: &Path, files: &Receiver<PathBuf>, fzf: &Fzf, skipped: &Skipped) -> Self {
     Self {
       config,
       cache: cache.clone(),
+      scope: scope.to_path_buf(),
       files: files.clone(),
       fzf: fzf.sink(),
+      skipped: skipped.clone(),
+      sent: Arc::default(),
     }
   }
 
-  pub fn run(self) -> JoinHandle<()> {
+  /// Sends the entries of the fresh cached files inside `dir`, sorted by `order`, so they are listed
+  /// before the walk finds them. Returns the keys of the files whose entries were sent, which are
+  /// not sent again when they are walked.
+  ///
+  /// Only files whose modified time and size are unchanged are sent, so no file is read and their
+  /// entries are listed as soon as the search starts. Other files are left to the workers. Cached
+  /// files that are no longer walked, such as files ignored since they were cached, are sent as well,
+  /// and are only removed from the cache once the walk completes.
+  pub fn send_cached(&self, dir: &Path, order: Order) -> Result<HashSet<PathBuf>, anyhow::Error> {
+    let mut sent = HashSet::new();
+
+    let (Some(root), Ok(dir), false) = (self.cache.root(), std::fs::canonicalize(dir), order == Order::Unordered) else {
+      return Ok(sent);
+    };
+
+    let mut files = Vec::new();
+
+    self.cache.for_each_file_info(|key, file_info| {
+      if let Ok(path) = root.join(key).strip_prefix(&dir) {
+        files.push((file_info.modified, path.to_path_buf()));
+      }
+    });
+
+    match order {
+      Order::Modified => files.sort_unstable_by(|(a, a_path), (b, b_path)| b.cmp(a).then_with(|| a_path.cmp(b_path))),
+      _ => files.sort_unstable_by(|(_, a), (_, b)| a.cmp(b)),
+    }
+
+    for (_, path) in files {
+      let Ok(Some(file)) = self.file(&path) else {
+        continue;
+      };
+
+      if self.use_cached_entries(&file, false).context("cached")? {
+        sent.extend(file.key);
+      }
+    }
+
+    Ok(sent)
+  }
+
+  /// Spawns a thread processing files until all files are processed, except the files whose
+  /// entries were `sent` before the walk.
+  ///
+  /// Returns an error if the worker stopped before all files were processed,
+  /// such as when `fzf` exits before indexing completes.
+  pub fn run(mut self, sent: &Arc<HashSet<PathBuf>>) -> JoinHandle<Result<(), anyhow::Error>> {
+    self.sent = sent.clone();
+
     std::thread::spawn(move || {
+      let mut state = ParseState::default();
+
       while let Ok(path) = self.files.recv() {
-        let modified = std::fs::metadata(&path).expect("metadata").modified().expect("modified");
+        self.process(&mut state, &path)?;
+      }
+
+      Ok(())
+    })
+  }
+
+  /// Sends the entries of a file, from the cache if they are fresh.
+  ///
+  /// Files that can't be read or parsed are skipped, and only errors sending entries are returned.
+  fn process(&self, state: &mut ParseState, path: &PathBuf) -> Result<(), anyhow::Error> {
+    let file = match self.file(path) {
+      Ok(Some(file)) => file,
+      Ok(None) => return Ok(()),
+      Err(err) => return self.skip(path, Skip::Unreadable(err)),
+    };
 
-        if self.use_cached_entries(&path, modified).expect("cached") {
-          continue;
-        }
+    if let Some(key) = &file.key {
+      self.cache.visit(key);
 
-        self.parse_file(&path, modified).expect("parse file");
+      if self.sent.contains(key) {
+        return Ok(());
       }
-    })
+    }
+
+    if self.use_cached_entries(&file, true).context("cached")? {
+      return Ok(());
+    }
+
+    self.parse_file(state, &file).context("parse file")
+  }
+
+  /// Returns the file at `path`, or `None` if its language is not configured.
+  fn file<'a>(&self, path: &'a PathBuf) -> Result<Option<File<'a>>, std::io::Error> {
+    // languages are detected from paths relative to the project root, which globs match.
+    let relative: PathBuf = self
+      .scope
+      .join(path)
+      .components()
+      .filter(|component| *component != Component::CurDir)
+      .collect();
+
+    let metadata = std::fs::metadata(path)?;
+    let (modified, size) = (metadata.modified()?, metadata.len());
+    let key = self.cache.key(path);
+
+    // the language detected from a file's content is reused while the file is unchanged,
+    // so the start of the file is not read again.
+    let mut detected = false;
+
+    let cached = || {
+      detected = true;
+
+      let file_info = self.cache.file_info(key.as_deref()?)?;
+
+      file_info.detected.clone().filter(|_| file_info.is_unchanged(modified, size))
+    };
+
+    // files without a configured language are listed when files are matched by name, glob or content.
+    let Some(parser) = Parser::from_path(self.config, relative, cached, || detect::read_head(path)) else {
+      return Ok(None);
+    };
+
+    Ok(Some(File {
+      path,
+      key,
+      modified,
+      size,
+      parser,
+      detected,
+      content: OnceCell::new(),
+    }))
   }
 
   /// Attempts to use the cache to compute a paths entries.
   ///
-  /// Returns true if the cache's entries were used.
-  fn use_cached_entries(&self, path: &PathBuf, modified: SystemTime) -> Result<bool, anyhow::Error> {
-    if let Some(file_info) = self.cache.file_info(path) {
-      // if the cached file and the current file have the same modified timestamp,
-      // use the entries from the cache.
-      if modified == file_info.modified {
-        for Entry { loc, text, kind, .. } in &file_info.entries {
-          // cached entries don't contain paths so they are re-inserted here.
-          self.fzf.send(&Entry::new(&path, *loc, text, *kind)).context("send")?;
-        }
-
-        return Ok(true);
-      }
+  /// Returns true if the cache's entries were used. If `hash` is false, the entries are only used if the
+  /// file's modified time and size are unchanged, and its content is never read.
+  fn use_cached_entries(&self, file: &File, hash: bool) -> Result<bool, anyhow::Error> {
+    let Some(key) = &file.key else {
+      return Ok(false);
+    };
+
+    let Some(file_info) = self.cache.file_info(key) else {
+      return Ok(false);
+    };
+
+    // cached entries are stale if the file's language configuration changed.
+    if file_info.fingerprint != file.parser.fingerprint() {
+      return Ok(false);
+    }
+
+    // skipped files are parsed again if the limit they exceeded was raised or disabled.
+    if file_info
+      .skipped
+      .is_some_and(|skipped| !self.config.limits().still_exceeded(skipped))
+    {
+      return Ok(false);
+    }
+
+    // the cached entries are used if the file's content has not changed,
+    // even if its modified timestamp has.
+    let fresh = if hash {
+      file_info.is_fresh(file.modified, file.size, || file.content().ok().map(content_hash))
+    } else {
+      file_info.is_unchanged(file.modified, file.size)
+    };
+
+    if !fresh {
+      return Ok(false);
+    }
+
+    for entry in &file_info.entries {
+      // cached entries don't contain paths so they are re-inserted here.
+      self.fzf.send(&entry.at(file.path)).context("send")?;
     }
 
-    Ok(false)
+    let touch = file_info.modified != file.modified;
+    drop(file_info);
+
+    if touch {
+      self.cache.touch_file_info(key, file.modified);
+    }
+
+    Ok(true)
   }
 
-  /// Parses a file and inserts its entries into the cache.
-  fn parse_file(&self, path: &PathBuf, modified: SystemTime) -> Result<(), anyhow::Error> {
-    self.cache.new_file_info(path.clone(), modified);
+  /// Parses a file and inserts its entries into the cache, if it has a cache key.
+  fn parse_file(&self, state: &mut ParseState, file: &File) -> Result<(), anyhow::Error> {
+    let limits = self.config.limits();
 
-    let Some(parser) = Parser::from_path(self.config, &path) else {
-      return Ok(());
+    // the content of files that are too large is not read, so their hash is unknown.
+    if let Some(limit) = limits.check_size(file.size) {
+      return self.exceed(file, 0, limit);
+    }
+
+    let content = match file.content() {
+      Ok(content) => content,
+      Err(err) => return self.skip(file.path, Skip::Unreadable(err)),
+    };
+
+    if self.config.detection().binary && detect::is_binary(content) {
+      return self.skip(file.path, Skip::Binary);
+    }
+
+    let hash = content_hash(content);
+
+    if let Some(limit) = limits.check_lines(content) {
+      return self.exceed(file, hash, limit);
+    }
+
+    let content = self.config.detection().decode(content);
+
+    let tree = match file.parser.parse(state, &content) {
+      Ok(tree) => tree,
+      Err(err) => match err.downcast() {
+        Ok(limit) => return self.exceed(file, hash, limit),
+        Err(err) => return self.skip(file.path, Skip::Unparsable(err)),
+      },
     };
 
-    parser.on_symbol(|symbol| {
-      let entry = Entry::new(path, symbol.span.start, symbol.text, symbol.kind);
+    // entries are only cached once all of them were sent, so a file is never cached partially
+    // when fzf exits while its entries are being sent.
+    let mut entries = Vec::new();
+
+    let symbols = file.parser.on_symbol(state, &content, &tree, |mut symbol| {
+      symbol.span = content.original_span(symbol.span);
+      symbol.definition = content.original_span(symbol.definition);
+
+      let entry = Entry::from_symbol(file.path, &symbol);
 
       self.fzf.send(&entry).context("send")?;
 
-      self.cache.insert_entry(path, entry);
+      if file.key.is_some() {
+        entries.push(entry.map((), Into::into));
+      }
 
       Ok(())
-    })
+    });
+
+    if let Err(err) = symbols {
+      return match err.downcast() {
+        Ok(limit) => self.exceed(file, hash, limit),
+        Err(err) => Err(err),
+      };
+    }
+
+    if let Some(key) = &file.key {
+      self.cache.new_file_info(key.clone(), file.info(hash, None, entries));
+    }
+
+    Ok(())
+  }
+
+  /// Records that a file was skipped for exceeding a limit, caching it as such if it has a cache key.
+  ///
+  /// `hash` is the [`content_hash`] of the file, or zero if its content was not read.
+  ///
+  /// Files that timed out are not cached, as parsing may only have been slow because the system was busy.
+  fn exceed(&self, file: &File, hash: u64, limit: Limit) -> Result<(), anyhow::Error> {
+    if let Some(key) = file.key.as_ref().filter(|_| limit != Limit::ParseTimeout) {
+      let skipped = self.config.limits().exceeded(limit);
+
+      self.cache.new_file_info(key.clone(), file.info(hash, Some(skipped), Vec::new()));
+    }
+
+    self.skip(file.path, Skip::Limit(limit))
+  }
+
+  /// Records that a file was skipped, which doesn't stop the worker.
+  fn skip(&self, path: &Path, skip: Skip) -> Result<(), anyhow::Error> {
+    self.skipped.push(path, skip);
+
+    Ok(())
+  }
+}
+
+impl File<'_> {
+  /// Returns the content of the file, reading it if it was not yet read.
+  fn content(&self) -> Result<&[u8], std::io::Error> {
+    self.content.get_or_try_init(|| std::fs::read(self.path)).map(Vec::as_slice)
+  }
+
+  /// Returns the [`FileInfo`] caching this file, whose content has the [`content_hash`] `hash`.
+  fn info(&self, hash: u64, skipped: Option<Exceeded>, entries: Vec<Entry<(), String>>) -> FileInfo {
+    FileInfo {
+      modified: self.modified,
+      size: self.size,
+      hash,
+      fingerprint: self.parser.fingerprint(),
+      skipped,
+      detected: self.detected.then(|| self.parser.language().to_string()),
+      entries,
+    }
+  }
+}
+
+impl Skipped {
+  fn push(&self, path: &Path, skip: Skip) {
+    self.0.lock().push((path.to_path_buf(), skip));
+  }
+
+  /// Prints the number of files skipped for each reason, preceded by each skipped file and why
+  /// it was skipped if `verbose` is set.
+  ///
+  /// Files skipped for exceeding a limit during a previous search are not parsed again, and are not
+  /// reported, unless they timed out.
+  pub fn report(&self, verbose: bool) {
+    let skipped = self.0.lock();
+
+    if skipped.is_empty() {
+      return;
+    }
+
+    let mut counts = BTreeMap::<&str, usize>::new();
+
+    for (path, skip) in skipped.iter() {
+      if verbose {
+        eprintln!("skipped {path:?}: {skip}");
+      }
+
+      *counts.entry(skip.reason()).or_default() += 1;
+    }
+
+    let counts: Vec<_> = counts.into_iter().map(|(reason, count)| format!("{count} {reason}")).collect();
+
+    eprintln!("skipped {} files: {}", skipped.len(), counts.join(", "));
+  }
+}
+
+impl Skip {
+  /// Returns a short description of why a file was skipped.
+  fn reason(&self) -> &'static str {
+    match self {
+      Self::Unreadable(_) => "unreadable",
+      Self::Binary => "binary",
+      Self::Unparsable(_) => "unparsable",
+      Self::Limit(limit) => limit.reason(),
+    }
+  }
+}
+
+impl Display for Skip {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::Unreadable(err) => write!(f, "unreadable ({err})"),
+      Self::Unparsable(err) => write!(f, "unparsable ({err:#})"),
+      Self::Limit(limit) => limit.fmt(f),
+      skip => f.write_str(skip.reason()),
+    }
   }
 }